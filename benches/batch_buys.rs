@@ -0,0 +1,38 @@
+//! Compares per-op and batched handling of a workload of consecutive buys.
+//!
+//! Run with `cargo bench --bench batch_buys`.
+
+use capital_gains::simulation::{compute_taxes_with_config, Op, Operation, SimConfig};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const BUYS: usize = 100_000;
+const ITERATIONS: u32 = 20;
+
+/// Runs `compute_taxes_with_config` on the workload and returns the mean time per run.
+fn bench(operations: &[Op], config: &SimConfig) -> Duration {
+    // Warm up once before timing.
+    black_box(compute_taxes_with_config(operations, config).unwrap());
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(compute_taxes_with_config(black_box(operations), config).unwrap());
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let operations: Vec<Op> = (0..BUYS)
+        .map(|i| Op::new(Operation::Buy, 10.00 + (i % 100) as f64 / 100.0, i % 50 + 1))
+        .collect();
+
+    let per_op = bench(&operations, &SimConfig::default());
+    let batched = bench(
+        &operations,
+        &SimConfig {
+            batch_buys: true,
+            ..SimConfig::default()
+        },
+    );
+    println!("{BUYS} buys, per-op:  {per_op:?}");
+    println!("{BUYS} buys, batched: {batched:?}");
+}
@@ -0,0 +1,215 @@
+use std::fmt::Display;
+
+/// Represents an error raised while simulating a series of operations.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimError {
+    /// The operations could not be parsed from JSON.
+    Parse(ParseError),
+    /// A sell operation tried to sell more shares than the portfolio holds.
+    Oversell {
+        /// The quantity of shares the operation tried to sell.
+        requested: usize,
+        /// The quantity of shares held in the portfolio.
+        available: usize,
+    },
+    /// A sell or return-of-capital operation needed a long position while the
+    /// portfolio holds none.
+    NoPosition {
+        /// The quantity of shares the operation was on.
+        quantity: usize,
+    },
+    /// A buy operation tried to cover more shares than the portfolio is short.
+    Overcover {
+        /// The quantity of shares the operation tried to buy.
+        requested: usize,
+        /// The quantity of shares sold short in the portfolio.
+        available: usize,
+    },
+    /// A transfer operation tried to move shares into a short position.
+    ShortTransfer {
+        /// The quantity of shares the operation tried to transfer.
+        quantity: usize,
+    },
+    /// A sell operation was smaller than the minimum lot and did not liquidate the position.
+    BelowMinLot {
+        /// The quantity of shares the operation tried to sell.
+        quantity: usize,
+        /// The minimum quantity of shares of a sell.
+        minimum: usize,
+    },
+    /// A return-of-capital operation was paid on fewer shares than the portfolio holds.
+    PartialDistribution {
+        /// The quantity of shares the distribution was paid on.
+        quantity: usize,
+        /// The quantity of shares held in the portfolio.
+        held: usize,
+    },
+    /// The fills of a buy operation add up to no shares.
+    EmptyFills,
+    /// An operation other than a buy has fills.
+    UnexpectedFills,
+    /// A long portfolio and a short portfolio cannot be merged into one.
+    MixedMerge {
+        /// The share count of the portfolio being merged into.
+        shares: i64,
+        /// The share count of the portfolio being merged.
+        other_shares: i64,
+    },
+    /// A cancel operation referenced no earlier operation still in effect.
+    CancelNotFound {
+        /// The note of the cancel, identifying the operation to cancel.
+        id: String,
+    },
+    /// An operation reused the note of an earlier operation still in effect.
+    DuplicateNote {
+        /// The note shared by both operations.
+        id: String,
+    },
+    /// The total value of an operation is too large to be represented.
+    Overflow {
+        /// The unit cost of the shares in the operation.
+        unit_cost: f64,
+        /// The quantity of shares involved in the operation.
+        quantity: usize,
+    },
+    /// An error raised by the operation at the given position in a series.
+    Operation {
+        /// The position of the failing operation, starting at 0.
+        index: usize,
+        /// The error raised by the operation.
+        error: Box<SimError>,
+    },
+}
+
+impl SimError {
+    /// Returns a short, machine readable name for the kind of error.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SimError::Parse(_) => "parse",
+            SimError::Oversell { .. } => "oversell",
+            SimError::NoPosition { .. } => "no_position",
+            SimError::Overcover { .. } => "overcover",
+            SimError::ShortTransfer { .. } => "short_transfer",
+            SimError::BelowMinLot { .. } => "below_min_lot",
+            SimError::PartialDistribution { .. } => "partial_distribution",
+            SimError::EmptyFills => "empty_fills",
+            SimError::UnexpectedFills => "unexpected_fills",
+            SimError::MixedMerge { .. } => "mixed_merge",
+            SimError::CancelNotFound { .. } => "cancel_not_found",
+            SimError::DuplicateNote { .. } => "duplicate_note",
+            SimError::Overflow { .. } => "overflow",
+            SimError::Operation { error, .. } => error.kind(),
+        }
+    }
+}
+
+impl Display for SimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimError::Parse(error) => error.fmt(f),
+            SimError::Oversell {
+                requested,
+                available,
+            } => write!(
+                f,
+                "cannot sell {requested} shares, only {available} available"
+            ),
+            SimError::NoPosition { quantity } => {
+                write!(
+                    f,
+                    "cannot apply an operation on {quantity} shares, no shares are held"
+                )
+            }
+            SimError::Overcover {
+                requested,
+                available,
+            } => write!(
+                f,
+                "cannot buy {requested} shares to cover, only {available} sold short"
+            ),
+            SimError::ShortTransfer { quantity } => {
+                write!(f, "cannot transfer {quantity} shares into a short position")
+            }
+            SimError::BelowMinLot { quantity, minimum } => write!(
+                f,
+                "cannot sell {quantity} shares, the minimum lot is {minimum}"
+            ),
+            SimError::PartialDistribution { quantity, held } => write!(
+                f,
+                "cannot return capital on {quantity} shares, {held} are held"
+            ),
+            SimError::EmptyFills => write!(f, "the fills of a buy add up to no shares"),
+            SimError::UnexpectedFills => write!(f, "only buy operations can have fills"),
+            SimError::MixedMerge {
+                shares,
+                other_shares,
+            } => write!(
+                f,
+                "cannot merge a position of {shares} shares with one of {other_shares} shares"
+            ),
+            SimError::CancelNotFound { id } => {
+                write!(f, "cannot cancel {id:?}, no such operation in effect")
+            }
+            SimError::DuplicateNote { id } => {
+                write!(f, "note {id:?} is already used by an operation in effect")
+            }
+            SimError::Overflow {
+                unit_cost,
+                quantity,
+            } => write!(
+                f,
+                "the total value of {quantity} shares at {unit_cost} overflows"
+            ),
+            SimError::Operation { index, error } => write!(f, "operation {index}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+impl From<ParseError> for SimError {
+    fn from(error: ParseError) -> Self {
+        SimError::Parse(error)
+    }
+}
+
+/// Represents an error raised while parsing operations from JSON.
+///
+/// The position of the problem in the input is preserved from serde_json.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// The line of the input where the error occurred, starting at 1.
+    pub line: usize,
+    /// The column of the input where the error occurred, starting at 1.
+    pub column: usize,
+    /// A description of the problem.
+    pub message: String,
+}
+
+impl From<serde_json::Error> for ParseError {
+    fn from(error: serde_json::Error) -> Self {
+        // serde_json appends the position to its message; keep only the description.
+        let message = error.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(index) => message[..index].to_string(),
+            None => message,
+        };
+        Self {
+            line: error.line(),
+            column: error.column(),
+            message,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at line {} column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
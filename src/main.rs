@@ -1,22 +1,235 @@
+use serde::de::DeserializeOwned;
 use serde_json::json;
 
-use simulation::{compute_taxes, Op};
+use capital_gains::error::{ParseError, SimError};
+use capital_gains::simulation::{compute_taxes_verbose, Op, State, Tax, TaxDetail, TaxEngine};
 use std::io::{self, BufRead};
+use std::process::ExitCode;
 
-mod simulation;
+/// The usage message printed when the arguments cannot be parsed.
+const USAGE: &str = "usage: capital_gains [--ndjson] [--cents] [--verbose] [--trace]";
 
-fn main() {
+/// The number of operations of the session shared by object lines that can still be cancelled.
+const SESSION_HISTORY_LIMIT: usize = 1000;
+
+/// Command line options of the binary.
+#[derive(Debug, Default)]
+struct Options {
+    /// Print every tax on its own line instead of one array per batch.
+    ndjson: bool,
+    /// Print taxes as integer cents instead of decimal amounts.
+    cents: bool,
+    /// Print the details of every tax computed for a batch.
+    verbose: bool,
+    /// Print a table of the state after every operation instead of JSON.
+    trace: bool,
+}
+
+impl Options {
+    /// Parses the options from the command line arguments.
+    ///
+    /// # Returns
+    ///
+    /// The parsed `Options`, or the first argument that is not a known option.
+    fn from_args() -> Result<Self, String> {
+        let mut options = Options::default();
+        for arg in std::env::args().skip(1) {
+            match arg.as_str() {
+                "--ndjson" => options.ndjson = true,
+                "--cents" => options.cents = true,
+                "--verbose" => options.verbose = true,
+                "--trace" => options.trace = true,
+                _ => return Err(arg),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Formats the tax of an operation as a JSON object, along with its details if verbose.
+    fn tax_json(&self, detail: &TaxDetail) -> serde_json::Value {
+        let tax = Tax::new(detail.tax);
+        let mut output = if self.verbose {
+            json!(detail)
+        } else {
+            json!(tax)
+        };
+        if self.cents {
+            output["tax"] = json!(tax.to_cents());
+            if self.verbose {
+                output["loss-applied"] = json!(Tax::new(detail.loss_applied).to_cents());
+            }
+        }
+        output
+    }
+
+    /// Computes the taxes of a batch and formats them as JSON output lines.
+    fn batch_json(&self, operations: &[Op]) -> Result<Vec<serde_json::Value>, SimError> {
+        let taxes: Vec<serde_json::Value> = compute_taxes_verbose(operations)?
+            .iter()
+            .map(|detail| self.tax_json(detail))
+            .collect();
+        if self.ndjson {
+            Ok(taxes)
+        } else {
+            Ok(vec![serde_json::Value::Array(taxes)])
+        }
+    }
+}
+
+/// Computes the taxes of a batch and prints them as rows of the trace table.
+fn batch_trace(operations: &[Op]) -> Result<Vec<serde_json::Value>, SimError> {
+    for (op, detail) in operations.iter().zip(compute_taxes_verbose(operations)?) {
+        print_trace_row(op, &detail.state, &Tax::new(detail.tax));
+    }
+    Ok(Vec::new())
+}
+
+/// Prints the header of the trace table.
+fn print_trace_header() {
+    println!(
+        "{:<18} {:>12} {:>10} {:>10} {:>12} {:>14} {:>12}",
+        "operation", "unit-cost", "quantity", "shares", "average", "loss", "tax"
+    );
+}
+
+/// Prints an operation and the state it results in as a row of the trace table.
+fn print_trace_row(op: &Op, state: &State, tax: &Tax) {
+    println!(
+        "{:<18} {:>12.2} {:>10} {:>10} {:>12.2} {:>14.2} {:>12}",
+        op.operation.name(),
+        op.unit_cost,
+        op.quantity,
+        state.total_shares,
+        state.weighted_average,
+        state.accumulated_loss,
+        tax.to_string()
+    );
+}
+
+/// Prints a structured error for an input line to stderr.
+fn report_error(line: usize, error: &SimError) {
+    eprintln!(
+        "{}",
+        json!({ "line": line, "error": error.to_string(), "kind": error.kind() })
+    );
+}
+
+/// Prints a structured parse error for an input line to stderr.
+fn report_parse_error(line: usize, error: &ParseError) {
+    eprintln!(
+        "{}",
+        json!({
+            "line": line,
+            "column": error.column,
+            "error": error.to_string(),
+            "kind": "parse",
+        })
+    );
+}
+
+/// Prints a structured error for an input line that could not be read to stderr.
+fn report_io_error(line: usize, error: &io::Error) {
+    eprintln!(
+        "{}",
+        json!({ "line": line, "error": error.to_string(), "kind": "io" })
+    );
+}
+
+/// Parses every back-to-back JSON value on a line and prints the outputs of `handle` for each,
+/// one per output line.
+///
+/// # Returns
+///
+/// `true` if every value on the line was parsed and handled successfully.
+fn process_line<T, F>(line: &str, line_number: usize, mut handle: F) -> bool
+where
+    T: DeserializeOwned,
+    F: FnMut(T) -> Result<Vec<serde_json::Value>, SimError>,
+{
+    let mut succeeded = true;
+    for value in serde_json::Deserializer::from_str(line).into_iter::<T>() {
+        let value = match value {
+            Ok(value) => value,
+            Err(error) => {
+                report_parse_error(line_number, &ParseError::from(error));
+                return false;
+            }
+        };
+        match handle(value) {
+            // Print JSON output
+            Ok(outputs) => {
+                for output in outputs {
+                    println!("{}", output);
+                }
+            }
+            Err(error) => {
+                report_error(line_number, &error);
+                succeeded = false;
+            }
+        }
+    }
+    succeeded
+}
+
+fn main() -> ExitCode {
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(arg) => {
+            eprintln!("unknown argument: {arg}");
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let mut failed = false;
+    // Single operations are applied incrementally to a session shared by all object lines
+    let mut session = TaxEngine::new().with_history_limit(SESSION_HISTORY_LIMIT);
+    if options.trace {
+        print_trace_header();
+    }
     let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        let line = line.unwrap();
+    for (index, line) in stdin.lock().lines().enumerate() {
+        let line_number = index + 1;
+        let line = match line {
+            Ok(line) => line,
+            Err(error) => {
+                report_io_error(line_number, &error);
+                failed = true;
+                // A line that is not valid UTF-8 is skipped, any other error ends the input
+                if error.kind() == io::ErrorKind::InvalidData {
+                    continue;
+                }
+                break;
+            }
+        };
         if line.trim().is_empty() {
             break;
         }
 
-        // Parse JSON input
-        let operations: Vec<Op> = serde_json::from_str(&line).expect("JSON inválido");
-        let taxes = compute_taxes(&operations);
-        // Print JSON output
-        println!("{}", json!(taxes));
+        // Parse JSON input, either operation objects or arrays of operations
+        let succeeded = if line.trim_start().starts_with('{') {
+            process_line(&line, line_number, |op: Op| {
+                let detail = session.push_verbose(&op)?;
+                if options.trace {
+                    print_trace_row(&op, &detail.state, &Tax::new(detail.tax));
+                    return Ok(Vec::new());
+                }
+                Ok(vec![options.tax_json(&detail)])
+            })
+        } else if options.trace {
+            process_line(&line, line_number, |operations: Vec<Op>| {
+                batch_trace(&operations)
+            })
+        } else {
+            process_line(&line, line_number, |operations: Vec<Op>| {
+                options.batch_json(&operations)
+            })
+        };
+        failed |= !succeeded;
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
     }
 }
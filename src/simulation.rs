@@ -1,22 +1,123 @@
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::io::Read;
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::{ParseError, SimError};
+
 /// The upper limit for non taxable operations.
 const TAXABLE_AMOUNT: f64 = 20000.00;
 
 const TAX_AMOUNT: f64 = 0.2;
 
+/// Configures the tax rules applied by the simulation.
+///
+/// The default configuration reproduces the standard rules: a 20% rate on
+/// profits, with sells of up to 20000.00 in total value exempt.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// The rate applied to taxable profits.
+    pub tax_rate: f64,
+    /// The upper limit for non taxable sell operations.
+    pub threshold: f64,
+    /// Positive taxes below this amount are waived.
+    pub min_tax: f64,
+    /// The maximum accumulated loss carried forward, if limited.
+    pub max_loss_carryforward: Option<f64>,
+    /// Whether a sell with no shares held may open a short position.
+    pub allow_short: bool,
+    /// Whether realized profits and accumulated losses are summed as integer
+    /// cents, so the accumulated loss is exact and results are reproducible
+    /// whatever the order in which amounts are summed.
+    pub stable_arithmetic: bool,
+    /// Whether profits from exempt sells still reduce the accumulated loss.
+    pub exempt_gains_consume_loss: bool,
+    /// Whether operation prices are converted to the home currency using their `fx_rate`.
+    pub convert_to_base: bool,
+    /// Whether runs of consecutive buys are added to the position in a single
+    /// pass, rounding the weighted average once per run instead of once per buy.
+    pub batch_buys: bool,
+    /// The minimum quantity of a sell, unless it liquidates the whole position.
+    pub min_sell_quantity: Option<usize>,
+    /// The exemption thresholds of asset classes, overriding `threshold` for
+    /// operations of these classes.
+    pub class_thresholds: HashMap<String, f64>,
+    /// Whether sells up to the threshold are exempt. When disabled, every
+    /// profitable sell is taxed, giving a worst-case estimate.
+    pub apply_threshold_exemption: bool,
+}
+
+impl SimConfig {
+    /// Clamps an accumulated loss to the configured carryforward ceiling.
+    fn cap_loss(&self, accumulated_loss: f64) -> f64 {
+        match self.max_loss_carryforward {
+            Some(max) => accumulated_loss.min(max),
+            None => accumulated_loss,
+        }
+    }
+
+    /// Returns the exemption threshold of the asset class of an operation,
+    /// falling back to the global threshold.
+    fn threshold_for(&self, op: &Op) -> f64 {
+        op.asset_class
+            .as_ref()
+            .and_then(|class| self.class_thresholds.get(class))
+            .copied()
+            .unwrap_or(self.threshold)
+    }
+
+    /// Rounds an amount to whole cents when stable arithmetic is enabled.
+    fn quantize(&self, amount: f64) -> f64 {
+        if self.stable_arithmetic {
+            round_cents(amount)
+        } else {
+            amount
+        }
+    }
+
+    /// Adds two amounts, as integer cents when stable arithmetic is enabled.
+    fn sum(&self, amount: f64, other: f64) -> f64 {
+        if self.stable_arithmetic {
+            to_cents(amount).saturating_add(to_cents(other)) as f64 / 100.0
+        } else {
+            amount + other
+        }
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            tax_rate: TAX_AMOUNT,
+            threshold: TAXABLE_AMOUNT,
+            min_tax: 0.0,
+            max_loss_carryforward: None,
+            allow_short: false,
+            stable_arithmetic: false,
+            exempt_gains_consume_loss: false,
+            convert_to_base: false,
+            batch_buys: false,
+            min_sell_quantity: None,
+            class_thresholds: HashMap::new(),
+            apply_threshold_exemption: true,
+        }
+    }
+}
+
 /// Represents the current state of a stock portfolio.
 ///
 /// This struct holds information about the total number of shares,
 /// the weighted average price of the shares, and any accumulated losses.
-#[derive(Debug, Default, Clone)]
+/// A negative share count represents a short position, in which case the
+/// weighted average is the average price the shares were sold short at.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct State {
-    /// The total number of shares in the portfolio.
-    pub total_shares: usize,
+    /// The total number of shares in the portfolio, negative when short.
+    pub total_shares: i64,
     /// The weighted average price of all shares in the portfolio.
-    pub weighted_avarage: f64,
+    pub weighted_average: f64,
     /// The total accumulated loss, if any.
     pub accumulated_loss: f64,
 }
@@ -26,91 +127,631 @@ impl State {
     ///
     /// # Arguments
     ///
-    /// * `total_shares` - The total number of shares in the portfolio.
-    /// * `weighted_avarage` - The weighted average price of all shares.
+    /// * `total_shares` - The total number of shares in the portfolio, negative when short.
+    /// * `weighted_average` - The weighted average price of all shares.
     /// * `accumulated_loss` - The total accumulated loss.
     ///
     /// # Returns
     ///
     /// A new `State` instance.
-    pub fn new(total_shares: usize, weighted_avarage: f64, accumulated_loss: f64) -> Self {
+    pub fn new(total_shares: i64, weighted_average: f64, accumulated_loss: f64) -> Self {
         Self {
             total_shares,
-            weighted_avarage,
+            weighted_average,
             accumulated_loss,
         }
     }
 
+    /// Merges two portfolios into one, as when consolidating accounts.
+    ///
+    /// Shares and accumulated losses are summed, and the weighted average is
+    /// weighted by the share count of each portfolio. Both portfolios must be
+    /// on the same side (both long or both short), or empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The portfolio to merge with this one.
+    ///
+    /// # Returns
+    ///
+    /// A new `State` holding the combined portfolio, a `SimError::MixedMerge`
+    /// if one portfolio is long and the other short, or a `SimError::Overflow`
+    /// if the combined share count overflows.
+    pub fn merge(&self, other: &State) -> Result<State, SimError> {
+        if self.total_shares.signum() * other.total_shares.signum() < 0 {
+            return Err(SimError::MixedMerge {
+                shares: self.total_shares,
+                other_shares: other.total_shares,
+            });
+        }
+
+        let total_shares =
+            self.total_shares
+                .checked_add(other.total_shares)
+                .ok_or(SimError::Overflow {
+                    unit_cost: other.weighted_average,
+                    quantity: other.total_shares.unsigned_abs() as usize,
+                })?;
+        let weighted_average = if total_shares == 0 {
+            0.0
+        } else {
+            calculate_weighted_average(
+                self.total_shares.unsigned_abs() as usize,
+                self.weighted_average,
+                other.total_shares.unsigned_abs() as usize,
+                other.weighted_average,
+            )
+        };
+        Ok(State::new(
+            total_shares,
+            weighted_average,
+            self.accumulated_loss + other.accumulated_loss,
+        ))
+    }
+
+    /// Returns the weighted average price of all shares in the portfolio.
+    #[deprecated(note = "use the `weighted_average` field instead")]
+    pub fn weighted_avarage(&self) -> f64 {
+        self.weighted_average
+    }
+
+    /// Calculates the market value of the portfolio at the given price.
+    ///
+    /// # Arguments
+    ///
+    /// * `current_price` - The current market price of the shares.
+    ///
+    /// # Returns
+    ///
+    /// The market value as a `f64`, negative for a short position.
+    pub fn market_value(&self, current_price: f64) -> f64 {
+        round_cents(self.total_shares as f64 * current_price)
+    }
+
+    /// Calculates the gain the portfolio would realize if closed at the given price.
+    ///
+    /// # Arguments
+    ///
+    /// * `current_price` - The current market price of the shares.
+    ///
+    /// # Returns
+    ///
+    /// The difference between the market value and the cost basis as a `f64`.
+    pub fn unrealized_gain(&self, current_price: f64) -> f64 {
+        round_cents((current_price - self.weighted_average) * self.total_shares as f64)
+    }
+
+    /// Calculates the break-even price of the position.
+    ///
+    /// This is the highest unit price at which selling the whole position
+    /// produces no tax, either because the sell is exempt or because its
+    /// profit is fully offset by the accumulated loss.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The tax rules to apply.
+    ///
+    /// # Returns
+    ///
+    /// The break-even price as a `f64`, or the weighted average when no long
+    /// position is held.
+    pub fn break_even_price(&self, config: &SimConfig) -> f64 {
+        if self.total_shares <= 0 {
+            return self.weighted_average;
+        }
+
+        let shares = self.total_shares as f64;
+        let offset_price = self.weighted_average + self.accumulated_loss / shares;
+        if !config.apply_threshold_exemption {
+            return offset_price;
+        }
+        let exempt_price = config.threshold / shares;
+        offset_price.max(exempt_price)
+    }
+
+    /// Calculates the largest quantity of shares that can be sold at a price
+    /// without paying tax because the sell stays under the threshold.
+    ///
+    /// A sell at or below the weighted average realizes no profit and is
+    /// tax-free at any size, so the whole position is returned in that case.
+    ///
+    /// # Arguments
+    ///
+    /// * `unit_cost` - The unit price of the sell.
+    /// * `asset_class` - The asset class of the sell, which selects its threshold
+    ///   in `config.class_thresholds`.
+    /// * `config` - The tax rules to apply.
+    ///
+    /// # Returns
+    ///
+    /// The maximum tax-free quantity, at most the shares held, or `0` when no
+    /// long position is held or the exemption is disabled for a profitable sell.
+    pub fn max_tax_free_quantity(
+        &self,
+        unit_cost: f64,
+        asset_class: Option<&str>,
+        config: &SimConfig,
+    ) -> usize {
+        if self.total_shares <= 0 {
+            return 0;
+        }
+
+        let total_shares = self.total_shares as usize;
+        if unit_cost <= self.weighted_average {
+            return total_shares;
+        }
+        if !config.apply_threshold_exemption {
+            return 0;
+        }
+        let sell = Op {
+            asset_class: asset_class.map(str::to_string),
+            ..Op::new(Operation::Sell, unit_cost, 0)
+        };
+        ((config.threshold_for(&sell) / unit_cost).floor() as usize).min(total_shares)
+    }
+
+    /// Applies a batch of operations to the portfolio.
+    ///
+    /// This lets a portfolio, and its accumulated loss, carry over from one
+    /// batch of operations to the next.
+    ///
+    /// # Arguments
+    ///
+    /// * `operations` - A slice of `Op` representing the batch of operations.
+    ///
+    /// # Returns
+    ///
+    /// A tuple containing the new `State` after the batch and the `Tax` for each
+    /// operation, or the first `SimError` raised by an operation.
+    pub fn apply_batch(&self, operations: &[Op]) -> Result<(State, Vec<Tax>), SimError> {
+        let (taxes, state) = run_operations(self.clone(), operations, &SimConfig::default())?;
+        Ok((state, taxes))
+    }
+
+    /// Computes the tax a sell would incur, without changing the portfolio.
+    ///
+    /// # Arguments
+    ///
+    /// * `unit_cost` - The unit price of the hypothetical sell.
+    /// * `quantity` - The quantity of shares of the hypothetical sell.
+    /// * `config` - The tax rules to apply.
+    ///
+    /// # Returns
+    ///
+    /// The `Tax` the sell would incur, or the `SimError` it would raise.
+    pub fn simulate_sell(
+        &self,
+        unit_cost: f64,
+        quantity: usize,
+        config: &SimConfig,
+    ) -> Result<Tax, SimError> {
+        let op = Op::new(Operation::Sell, unit_cost, quantity);
+        self.handle_sell(&op, config).map(|(_, tax)| tax)
+    }
+
     /// Handles a buy operation and computes the resulting state and tax.
     ///
+    /// A buy against a short position covers it, realizing the difference
+    /// between the short price and the buy price. A buy filled at several
+    /// prices is handled as a single buy of all its fills.
+    ///
     /// # Arguments
     ///
     /// * `op` - The buy operation to be handled.
+    /// * `config` - The tax rules to apply.
+    ///
+    /// # Returns
+    ///
+    /// A tuple containing the new `State` after the buy operation and the `Tax`
+    /// (always default unless covering a short), a `SimError::Overcover` if
+    /// the buy exceeds the short position, or a `SimError::Overflow` if the
+    /// share count would overflow.
+    pub fn handle_buy(&self, op: &Op, config: &SimConfig) -> Result<(Self, Tax), SimError> {
+        self.buy(op, config, &linear_tax)
+            .map(|(state, tax, _)| (state, tax))
+    }
+
+    fn buy(
+        &self,
+        op: &Op,
+        config: &SimConfig,
+        tax_fn: &TaxFn<'_>,
+    ) -> Result<(Self, Tax, Outcome), SimError> {
+        if !op.fills.is_empty() {
+            return self.buy(&op.combine_fills()?, config, tax_fn);
+        }
+
+        if self.total_shares < 0 {
+            let short_shares = self.total_shares.unsigned_abs() as usize;
+            if op.quantity > short_shares {
+                return Err(SimError::Overcover {
+                    requested: op.quantity,
+                    available: short_shares,
+                });
+            }
+
+            let profit = (self.weighted_average - op.unit_cost) * op.quantity as f64;
+            return self.realize(profit, op, config, tax_fn);
+        }
+
+        Ok((self.add_shares(op)?, Tax::default(), Outcome::default()))
+    }
+
+    /// Handles a transfer of shares into the portfolio and computes the resulting state and tax.
+    ///
+    /// Transferred shares (gifts, inheritances, account transfers) arrive with an
+    /// externally determined cost basis, given by `op.unit_cost`. They are folded
+    /// into the weighted average like a buy, but a transfer is never taxable.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The transfer operation to be handled.
+    ///
+    /// # Returns
+    ///
+    /// A tuple containing the new `State` after the transfer and the `Tax` (always default),
+    /// or a `SimError::ShortTransfer` if the portfolio holds a short position.
+    pub fn handle_transfer(&self, op: &Op) -> Result<(Self, Tax), SimError> {
+        if !op.fills.is_empty() {
+            return Err(SimError::UnexpectedFills);
+        }
+        if self.total_shares < 0 {
+            return Err(SimError::ShortTransfer {
+                quantity: op.quantity,
+            });
+        }
+
+        Ok((self.add_shares(op)?, Tax::default()))
+    }
+
+    /// Handles a return-of-capital distribution and computes the resulting state and tax.
+    ///
+    /// A return of capital of `op.unit_cost` per share, paid on `op.quantity`
+    /// shares, lowers the cost basis instead of being taxed. Once the basis
+    /// reaches zero, the rest of the distribution is a taxable gain, offset by
+    /// the accumulated loss like the profit of a sell.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The return-of-capital operation to be handled.
+    /// * `config` - The tax rules to apply.
     ///
     /// # Returns
     ///
-    /// A tuple containing the new `State` after the buy operation and the `Tax` (always default for buy operations).
-    pub fn handle_buy(&self, op: &Op) -> (Self, Tax) {
-        let new_weighted_avarage = calculate_weighted_avarage(
-            self.total_shares,
-            self.weighted_avarage,
+    /// A tuple containing the new `State` after the distribution and the `Tax`
+    /// on any distribution in excess of the cost basis, a `SimError::NoPosition`
+    /// if the portfolio holds no long position, or a `SimError::Oversell` or
+    /// `SimError::PartialDistribution` if `op.quantity` is not the number of
+    /// shares held.
+    pub fn handle_roc(&self, op: &Op, config: &SimConfig) -> Result<(Self, Tax), SimError> {
+        self.roc(op, config, &linear_tax)
+            .map(|(state, tax, _)| (state, tax))
+    }
+
+    fn roc(
+        &self,
+        op: &Op,
+        config: &SimConfig,
+        tax_fn: &TaxFn<'_>,
+    ) -> Result<(Self, Tax, Outcome), SimError> {
+        // Without shares there is no basis for the distribution to return.
+        if self.total_shares <= 0 {
+            return Err(SimError::NoPosition {
+                quantity: op.quantity,
+            });
+        }
+        // The basis is spread over every share, so the distribution must be paid on all of them.
+        let held = self.total_shares as usize;
+        if op.quantity > held {
+            return Err(SimError::Oversell {
+                requested: op.quantity,
+                available: held,
+            });
+        }
+        if op.quantity < held {
+            return Err(SimError::PartialDistribution {
+                quantity: op.quantity,
+                held,
+            });
+        }
+
+        let basis = self.weighted_average * self.total_shares as f64;
+        let excess = config.quantize((op.total_value() - basis).max(0.0));
+        let weighted_average =
+            round_cents((basis - op.total_value()).max(0.0) / self.total_shares as f64);
+
+        if excess == 0.0 {
+            let new_state = State::new(self.total_shares, weighted_average, self.accumulated_loss);
+            return Ok((new_state, Tax::default(), Outcome::default()));
+        }
+
+        let (accumulated_loss, tax, outcome) = self.tax_gain(excess, op, config, tax_fn);
+        Ok((
+            State::new(self.total_shares, weighted_average, accumulated_loss),
+            tax,
+            outcome,
+        ))
+    }
+
+    /// Adds `op.quantity` shares at `op.unit_cost` to a long position.
+    fn add_shares(&self, op: &Op) -> Result<Self, SimError> {
+        let total_shares = self
+            .total_shares
+            .checked_add(op.signed_quantity()?)
+            .ok_or_else(|| op.overflow())?;
+        let new_weighted_average = calculate_weighted_average(
+            self.total_shares as usize,
+            self.weighted_average,
             op.quantity,
             op.unit_cost,
         );
-        let new_state = State::new(
-            self.total_shares + op.quantity,
-            new_weighted_avarage,
+        if !new_weighted_average.is_finite() {
+            return Err(op.overflow());
+        }
+        Ok(State::new(
+            total_shares,
+            new_weighted_average,
             self.accumulated_loss,
-        );
-        (new_state, Tax::default())
+        ))
+    }
+
+    /// Adds a run of buys to a long position in a single pass.
+    ///
+    /// The weighted average is computed and rounded once for the whole run,
+    /// instead of once per buy. Every buy goes through the same checks as when
+    /// it is handled on its own.
+    fn add_buys(&self, buys: &[Op], config: &SimConfig) -> Result<Self, SimError> {
+        let mut quantity: usize = 0;
+        let mut total_value = 0.0;
+        for op in buys {
+            let op = op.prepare(config)?;
+            quantity = quantity
+                .checked_add(op.quantity)
+                .ok_or_else(|| op.overflow())?;
+            total_value += op.total_value();
+        }
+        if quantity == 0 {
+            return Ok(self.clone());
+        }
+
+        let op = Op::new(Operation::Buy, total_value / quantity as f64, quantity);
+        if op.total_value_checked().is_none() {
+            return Err(op.overflow());
+        }
+        self.add_shares(&op)
     }
 
     /// Handles a sell operation and computes the resulting state and tax.
     ///
+    /// When `config.allow_short` is set, a sell with no long position held
+    /// opens or extends a short position instead of failing.
+    ///
     /// # Arguments
     ///
     /// * `op` - The sell operation to be handled.
+    /// * `config` - The tax rules to apply.
+    ///
+    /// # Returns
+    ///
+    /// A tuple containing the new `State` after the sell operation and the `Tax`,
+    /// a `SimError::NoPosition` if the portfolio holds no shares at all, a
+    /// `SimError::Oversell` if it does not hold enough shares, or a
+    /// `SimError::BelowMinLot` if the sell is smaller than `config.min_sell_quantity`.
+    pub fn handle_sell(&self, op: &Op, config: &SimConfig) -> Result<(Self, Tax), SimError> {
+        self.sell(op, config, &linear_tax)
+            .map(|(state, tax, _)| (state, tax))
+    }
+
+    fn sell(
+        &self,
+        op: &Op,
+        config: &SimConfig,
+        tax_fn: &TaxFn<'_>,
+    ) -> Result<(Self, Tax, Outcome), SimError> {
+        if !op.fills.is_empty() {
+            return Err(SimError::UnexpectedFills);
+        }
+
+        if let Some(minimum) = config.min_sell_quantity {
+            let liquidates = i64::try_from(op.quantity) == Ok(self.total_shares);
+            if op.quantity < minimum && !liquidates {
+                return Err(SimError::BelowMinLot {
+                    quantity: op.quantity,
+                    minimum,
+                });
+            }
+        }
+
+        if config.allow_short && self.total_shares <= 0 {
+            let new_weighted_average = calculate_weighted_average(
+                self.total_shares.unsigned_abs() as usize,
+                self.weighted_average,
+                op.quantity,
+                op.unit_cost,
+            );
+            if !new_weighted_average.is_finite() {
+                return Err(op.overflow());
+            }
+            let total_shares = self
+                .total_shares
+                .checked_sub(op.signed_quantity()?)
+                .ok_or_else(|| op.overflow())?;
+            let new_state = State::new(total_shares, new_weighted_average, self.accumulated_loss);
+            return Ok((new_state, Tax::default(), Outcome::default()));
+        }
+
+        // Without a position there is no basis to compute a profit against.
+        if self.total_shares == 0 && op.quantity > 0 {
+            return Err(SimError::NoPosition {
+                quantity: op.quantity,
+            });
+        }
+
+        let long_shares = self.total_shares.max(0) as usize;
+        if op.quantity > long_shares {
+            return Err(SimError::Oversell {
+                requested: op.quantity,
+                available: long_shares,
+            });
+        }
+
+        let profit = (op.unit_cost - self.weighted_average) * op.quantity as f64;
+        self.realize(profit, op, config, tax_fn)
+    }
+
+    /// Closes `op.quantity` shares of the position and computes the tax on the realized profit.
+    ///
+    /// # Arguments
+    ///
+    /// * `profit` - The profit (or loss, when negative) realized by the operation.
+    /// * `op` - The operation closing the position.
+    /// * `config` - The tax rules to apply.
+    /// * `tax_fn` - The function computing the tax on the net taxable gain.
     ///
     /// # Returns
     ///
-    /// A tuple containing the new `State` after the sell operation and the `Tax`.
-    pub fn handle_sell(&self, op: &Op) -> (Self, Tax) {
-        let profit = (op.unit_cost - self.weighted_avarage) * op.quantity as f64;
+    /// A tuple containing the new `State`, the `Tax` and the `Outcome`, or a
+    /// `SimError::Overflow` if the quantity cannot be represented as a share count.
+    fn realize(
+        &self,
+        profit: f64,
+        op: &Op,
+        config: &SimConfig,
+        tax_fn: &TaxFn<'_>,
+    ) -> Result<(Self, Tax, Outcome), SimError> {
+        let profit = config.quantize(profit);
+        let quantity = op.signed_quantity()?;
+        let total_shares = if self.total_shares < 0 {
+            self.total_shares.checked_add(quantity)
+        } else {
+            self.total_shares.checked_sub(quantity)
+        }
+        .ok_or_else(|| op.overflow())?;
+
+        if profit == 0.0 {
+            let new_state = State::new(total_shares, self.weighted_average, self.accumulated_loss);
+            return Ok((new_state, Tax::default(), Outcome::default()));
+        }
 
-        if op.total_value() <= TAXABLE_AMOUNT && profit > 0.0 {
-            return (self.clone(), Tax::default());
+        // The exemption is defined on the value of a sell, so a buy covering a
+        // short position is never exempt.
+        let is_sell = self.total_shares > 0;
+        if config.apply_threshold_exemption
+            && is_sell
+            && op.total_value() <= config.threshold_for(op)
+            && profit > 0.0
+        {
+            let accumulated_loss = if config.exempt_gains_consume_loss {
+                config.sum(self.accumulated_loss, -profit).max(0.0)
+            } else {
+                self.accumulated_loss
+            };
+            let new_state = State::new(total_shares, self.weighted_average, accumulated_loss);
+            let outcome = Outcome {
+                gain: profit,
+                loss_applied: 0.0,
+            };
+            return Ok((new_state, Tax::default(), outcome));
         }
 
         if profit.is_sign_negative() {
             let new_state = State::new(
-                self.total_shares - op.quantity,
-                self.weighted_avarage,
-                self.accumulated_loss + profit.abs(),
+                total_shares,
+                self.weighted_average,
+                config.quantize(config.cap_loss(config.sum(self.accumulated_loss, -profit))),
             );
+            let outcome = Outcome {
+                gain: profit,
+                loss_applied: 0.0,
+            };
 
-            return (new_state, Tax::default());
+            return Ok((new_state, Tax::default(), outcome));
         }
 
-        let net_profit = (profit - self.accumulated_loss).max(0.0);
-        let tax = (net_profit * TAX_AMOUNT).round();
-        let new_accumulated_loss = (self.accumulated_loss - profit).max(0.0);
-        let new_state = State::new(
-            self.total_shares - op.quantity,
-            self.weighted_avarage,
+        let (new_accumulated_loss, tax, outcome) = self.tax_gain(profit, op, config, tax_fn);
+        let new_state = State::new(total_shares, self.weighted_average, new_accumulated_loss);
+        Ok((new_state, tax, outcome))
+    }
+
+    /// Computes the tax on a taxable gain, after offsetting it with the accumulated loss.
+    ///
+    /// # Arguments
+    ///
+    /// * `profit` - The taxable gain realized by the operation.
+    /// * `op` - The operation realizing the gain.
+    /// * `config` - The tax rules to apply.
+    /// * `tax_fn` - The function computing the tax on the net taxable gain.
+    ///
+    /// # Returns
+    ///
+    /// A tuple containing the remaining accumulated loss, the `Tax` and the `Outcome`.
+    fn tax_gain(
+        &self,
+        profit: f64,
+        op: &Op,
+        config: &SimConfig,
+        tax_fn: &TaxFn<'_>,
+    ) -> (f64, Tax, Outcome) {
+        let loss_applied = self.accumulated_loss.min(profit).max(0.0);
+        let net_profit = config.sum(profit, -loss_applied);
+        let tax = match op.tax_rate {
+            Some(tax_rate) => net_profit * tax_rate,
+            None => tax_fn(net_profit, config),
+        };
+        // Half values round away from zero.
+        let mut tax = tax.round();
+        if tax > 0.0 && tax < config.min_tax {
+            tax = 0.0;
+        }
+        let new_accumulated_loss =
+            config.quantize(config.cap_loss(config.sum(self.accumulated_loss, -profit).max(0.0)));
+        (
             new_accumulated_loss,
-        );
-        (new_state, Tax::new(tax))
+            Tax::new(tax),
+            Outcome {
+                gain: profit,
+                loss_applied,
+            },
+        )
     }
 }
 
 /// Represents the type of operation performed on the portfolio.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Operation {
     /// Represents a buy operation.
     Buy,
     /// Represents a sell operation.
     Sell,
+    /// Represents shares transferred in at an inherited cost basis.
+    Transfer,
+    /// Represents a return-of-capital distribution, which lowers the cost basis.
+    #[serde(rename = "return-of-capital")]
+    ReturnOfCapital,
+    /// Represents the cancellation of the earlier operation whose note matches
+    /// the note of the cancel.
+    ///
+    /// Only `TaxEngine` keeps the history needed to cancel, so a cancel passed
+    /// to `compute_taxes` or any other batch function always fails with
+    /// `SimError::CancelNotFound`. Operations without a note cannot be
+    /// cancelled. The tax of a cancel is the change in total tax it causes,
+    /// which is negative when tax paid earlier is reversed, so it should not be
+    /// summed as if it were a tax on a sale.
+    Cancel,
+}
+
+impl Operation {
+    /// Returns the name of the operation, as it is spelled in JSON.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Buy => "buy",
+            Operation::Sell => "sell",
+            Operation::Transfer => "transfer",
+            Operation::ReturnOfCapital => "return-of-capital",
+            Operation::Cancel => "cancel",
+        }
+    }
 }
 
 impl From<&'_ str> for Operation {
@@ -118,24 +759,77 @@ impl From<&'_ str> for Operation {
         match value {
             "buy" => Operation::Buy,
             "sell" => Operation::Sell,
+            "transfer" => Operation::Transfer,
+            "return-of-capital" => Operation::ReturnOfCapital,
+            "cancel" => Operation::Cancel,
             _ => panic!("Invalid operation"),
         }
     }
 }
 
 /// Represents a single operation performed on the portfolio.
-#[derive(Debug, Deserialize)]
+///
+/// Unknown fields are rejected so that a misspelled field is reported
+/// instead of silently ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Op {
-    /// The type of operation (buy or sell).
+    /// The type of operation (buy, sell, transfer or return of capital).
     pub operation: Operation,
     /// The unit cost of the shares in the operation.
     #[serde(rename = "unit-cost")]
     pub unit_cost: f64,
     /// The quantity of shares involved in the operation.
     pub quantity: usize,
+    /// The tax rate for this operation, overriding `SimConfig::tax_rate` when set.
+    #[serde(rename = "tax-rate", default, skip_serializing_if = "Option::is_none")]
+    pub tax_rate: Option<f64>,
+    /// The partial fills of a buy as `(unit cost, quantity)` pairs, overriding
+    /// `unit_cost` and `quantity` when not empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fills: Vec<(f64, usize)>,
+    /// The exchange rate of the operation, in home currency per unit of foreign
+    /// currency. Used when `SimConfig::convert_to_base` is set.
+    #[serde(rename = "fx-rate", default, skip_serializing_if = "Option::is_none")]
+    pub fx_rate: Option<f64>,
+    /// A broker-assigned ID or note, echoed in the verbose output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The asset class of the operation, selecting its exemption threshold
+    /// from `SimConfig::class_thresholds`.
+    #[serde(
+        rename = "asset-class",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub asset_class: Option<String>,
 }
 
 impl Op {
+    /// Creates a new `Op` instance without any of the optional fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - The type of operation.
+    /// * `unit_cost` - The unit cost of the shares in the operation.
+    /// * `quantity` - The quantity of shares involved in the operation.
+    ///
+    /// # Returns
+    ///
+    /// A new `Op` instance.
+    pub fn new(operation: Operation, unit_cost: f64, quantity: usize) -> Self {
+        Self {
+            operation,
+            unit_cost,
+            quantity,
+            tax_rate: None,
+            fills: Vec::new(),
+            fx_rate: None,
+            note: None,
+            asset_class: None,
+        }
+    }
+
     /// Calculates the total value of the operation.
     ///
     /// # Returns
@@ -144,10 +838,115 @@ impl Op {
     pub fn total_value(&self) -> f64 {
         self.unit_cost * self.quantity as f64
     }
+
+    /// Calculates the total value of the operation, checking for overflow.
+    ///
+    /// # Returns
+    ///
+    /// The total value as a `f64`, or `None` if it is not finite.
+    pub fn total_value_checked(&self) -> Option<f64> {
+        Some(self.total_value()).filter(|total_value| total_value.is_finite())
+    }
+
+    /// Returns the error for an operation whose amounts cannot be represented.
+    fn overflow(&self) -> SimError {
+        SimError::Overflow {
+            unit_cost: self.unit_cost,
+            quantity: self.quantity,
+        }
+    }
+
+    /// Returns the quantity of the operation as a signed share count.
+    fn signed_quantity(&self) -> Result<i64, SimError> {
+        i64::try_from(self.quantity).map_err(|_| self.overflow())
+    }
+
+    /// Converts the prices of the operation to the home currency.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the operation with `unit_cost` and fill prices multiplied by
+    /// `fx_rate`, or `None` if the operation has no exchange rate.
+    fn to_base(&self) -> Option<Op> {
+        let fx_rate = self.fx_rate?;
+        Some(Op {
+            unit_cost: self.unit_cost * fx_rate,
+            fills: self
+                .fills
+                .iter()
+                .map(|(unit_cost, quantity)| (unit_cost * fx_rate, *quantity))
+                .collect(),
+            fx_rate: None,
+            ..self.clone()
+        })
+    }
+
+    /// Prepares the operation to be applied under the given tax rules.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the operation converted to the home currency when
+    /// `config.convert_to_base` is set, with its fills combined, or the
+    /// `SimError` raised by its fills, or a `SimError::Overflow` if its total
+    /// value is not finite.
+    fn prepare(&self, config: &SimConfig) -> Result<Op, SimError> {
+        let op = match self.to_base() {
+            Some(op) if config.convert_to_base => op,
+            _ => self.clone(),
+        };
+        let op = op.combine_fills()?;
+        match op.total_value_checked() {
+            Some(_) => Ok(op),
+            None => Err(op.overflow()),
+        }
+    }
+
+    /// Combines the fills of the operation into a single fill.
+    ///
+    /// # Returns
+    ///
+    /// A copy of the operation without fills, whose `quantity` is the total
+    /// filled quantity and whose `unit_cost` is the average fill price, or an
+    /// unchanged copy if the operation has no fills. Fails with a
+    /// `SimError::UnexpectedFills` if the operation is not a buy, a
+    /// `SimError::EmptyFills` if the fills add up to no shares, or a
+    /// `SimError::Overflow` if their total overflows.
+    fn combine_fills(&self) -> Result<Op, SimError> {
+        if self.fills.is_empty() {
+            return Ok(self.clone());
+        }
+        if !matches!(self.operation, Operation::Buy) {
+            return Err(SimError::UnexpectedFills);
+        }
+
+        let quantity = self
+            .fills
+            .iter()
+            .try_fold(0usize, |total, (_, quantity)| total.checked_add(*quantity))
+            .ok_or_else(|| self.overflow())?;
+        if quantity == 0 {
+            return Err(SimError::EmptyFills);
+        }
+        let total_value: f64 = self
+            .fills
+            .iter()
+            .map(|(unit_cost, quantity)| unit_cost * *quantity as f64)
+            .sum();
+        let op = Op {
+            unit_cost: total_value / quantity as f64,
+            quantity,
+            fills: Vec::new(),
+            ..self.clone()
+        };
+        match op.total_value_checked() {
+            Some(_) => Ok(op),
+            None => Err(op.overflow()),
+        }
+    }
 }
 
 /// Represents the tax calculated for an operation.
-#[derive(Debug, Serialize, Default, PartialEq)]
+#[derive(Debug, Clone, Serialize, Default, PartialEq)]
 pub struct Tax {
     tax: f64,
 }
@@ -156,6 +955,31 @@ impl Tax {
     pub fn new(tax: f64) -> Self {
         Self { tax }
     }
+
+    /// Checks whether two taxes are equal within a tolerance.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The `Tax` to compare against.
+    /// * `epsilon` - The largest difference still considered equal.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the taxes differ by no more than `epsilon`.
+    pub fn approx_eq(&self, other: &Tax, epsilon: f64) -> bool {
+        (self.tax - other.tax).abs() <= epsilon
+    }
+
+    /// Converts the tax to an integer number of cents.
+    ///
+    /// # Returns
+    ///
+    /// The tax in cents, rounded half away from zero. Taxes beyond the range of
+    /// `i64` saturate at `i64::MAX` or `i64::MIN`.
+    pub fn to_cents(&self) -> i64 {
+        // Float to int `as` casts saturate at the bounds of the target type.
+        (self.tax * 100.0).round() as i64
+    }
 }
 
 impl Display for Tax {
@@ -177,50 +1001,591 @@ impl PartialEq<Tax> for f64 {
     }
 }
 
+/// Represents the tax calculated for an operation, along with how it was reached.
+#[derive(Debug, Serialize, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TaxDetail {
+    /// The tax calculated for the operation.
+    pub tax: f64,
+    /// The accumulated loss used to reduce the taxable gain of the operation.
+    pub loss_applied: f64,
+    /// The note of the operation, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The state of the portfolio after the operation.
+    #[serde(skip)]
+    pub state: State,
+}
+
+/// Represents the outcome of an operation, with every amount it produced.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct OpResult {
+    /// The tax calculated for the operation.
+    pub tax: Tax,
+    /// The gain realized by the operation, negative for a loss.
+    pub realized_gain: f64,
+    /// The accumulated loss used to reduce the taxable gain of the operation.
+    pub loss_applied: f64,
+    /// The net cash movement of the operation after tax, negative for a cost.
+    pub proceeds: f64,
+    /// The state of the portfolio after the operation.
+    pub state: State,
+}
+
+/// Represents how an operation reached its tax, besides the resulting state.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Outcome {
+    /// The gain realized by the operation, negative for a loss.
+    gain: f64,
+    /// The accumulated loss used to reduce the taxable gain of the operation.
+    loss_applied: f64,
+}
+
 /// Calculates the new weighted average price after a buy operation.
 ///
 /// # Arguments
 ///
 /// * `total_shares` - The current total number of shares.
-/// * `weighted_avarage` - The current weighted average price.
+/// * `weighted_average` - The current weighted average price.
 /// * `new_quantity` - The quantity of shares in the new operation.
 /// * `new_unit_price` - The unit price of shares in the new operation.
 ///
 /// # Returns
 ///
-/// The new weighted average price as a `f64`.
-pub fn calculate_weighted_avarage(
+/// The new weighted average price as a `f64`, or `weighted_average` unchanged
+/// when there are no shares at all.
+pub fn calculate_weighted_average(
     total_shares: usize,
-    weighted_avarage: f64,
+    weighted_average: f64,
     new_quantity: usize,
     new_unit_price: f64,
 ) -> f64 {
-    let result = ((total_shares as f64 * weighted_avarage)
+    if total_shares == 0 && new_quantity == 0 {
+        return weighted_average;
+    }
+    let result = ((total_shares as f64 * weighted_average)
         + (new_quantity as f64 * new_unit_price))
         / (total_shares as f64 + new_quantity as f64);
-    (result * 100.0).round() / 100.0
+    round_cents(result)
 }
 
-/// Computes taxes for a series of operations.
-///
+/// Calculates the new weighted average price after a buy operation.
+#[deprecated(note = "renamed to `calculate_weighted_average`")]
+pub fn calculate_weighted_avarage(
+    total_shares: usize,
+    weighted_average: f64,
+    new_quantity: usize,
+    new_unit_price: f64,
+) -> f64 {
+    calculate_weighted_average(total_shares, weighted_average, new_quantity, new_unit_price)
+}
+
+/// Rounds an amount to two decimal places.
+fn round_cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+/// Converts an amount to a whole number of cents, saturating at the bounds of `i64`.
+fn to_cents(amount: f64) -> i64 {
+    (amount * 100.0).round() as i64
+}
+
+/// Parses a series of operations from a JSON array.
+///
+/// # Arguments
+///
+/// * `line` - The JSON array of operations, as found on one line of input.
+///
+/// # Returns
+///
+/// A vector of `Op`, or a `ParseError` locating the problem in the input.
+pub fn parse_ops(line: &str) -> Result<Vec<Op>, ParseError> {
+    Ok(serde_json::from_str(line)?)
+}
+
+/// Serializes a portfolio state to JSON, so that it can be resumed later.
+///
+/// # Arguments
+///
+/// * `state` - The `State` to be saved.
+///
+/// # Returns
+///
+/// The JSON representation of the state.
+pub fn save_state(state: &State) -> String {
+    serde_json::to_string(state).expect("State always serializes to JSON")
+}
+
+/// Restores a portfolio state saved with `save_state`.
+///
+/// # Arguments
+///
+/// * `json` - The JSON representation of the state.
+///
+/// # Returns
+///
+/// The restored `State`, or a `ParseError` locating the problem in the input.
+pub fn load_state(json: &str) -> Result<State, ParseError> {
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Computes taxes for every batch in a JSON document holding an array of operation arrays.
+///
+/// Each batch is computed independently, starting from an empty portfolio,
+/// as if it had been given on its own line.
+///
+/// # Arguments
+///
+/// * `reader` - The source of the JSON document.
+///
+/// # Returns
+///
+/// A vector with the computed taxes of each batch, or the first `SimError` raised.
+pub fn compute_all_batches<R: Read>(reader: R) -> Result<Vec<Vec<Tax>>, SimError> {
+    let batches: Vec<Vec<Op>> = serde_json::from_reader(reader).map_err(ParseError::from)?;
+    batches.iter().map(|batch| compute_taxes(batch)).collect()
+}
+
+/// Checks that a series of operations never sells more shares than are held.
+///
+/// Only a running share count is kept, so this is much cheaper than computing
+/// the taxes, and suitable for validating input up front.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+///
+/// # Returns
+///
+/// `Ok(())` if every operation can be executed, or a `SimError::Operation`
+/// holding the index of the first operation that cannot.
+pub fn can_execute(operations: &[Op]) -> Result<(), SimError> {
+    can_execute_with_config(operations, &SimConfig::default())
+}
+
+/// Checks that a series of operations can be executed under the given tax rules.
+///
+/// When `config.allow_short` is set, sells with no long position held open
+/// a short instead of failing, and sells below `config.min_sell_quantity` fail
+/// unless they liquidate the position, following the same rules as
+/// `State::handle_sell`.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+/// * `config` - The tax rules to apply.
+///
+/// # Returns
+///
+/// `Ok(())` if every operation can be executed, or a `SimError::Operation`
+/// holding the index of the first operation that cannot.
+pub fn can_execute_with_config(operations: &[Op], config: &SimConfig) -> Result<(), SimError> {
+    let mut total_shares: i64 = 0;
+    for (index, op) in operations.iter().enumerate() {
+        let quantity = op
+            .combine_fills()
+            .map_err(|error| SimError::Operation {
+                index,
+                error: Box::new(error),
+            })?
+            .quantity;
+        let short_shares = if total_shares < 0 {
+            total_shares.unsigned_abs() as usize
+        } else {
+            0
+        };
+        let below_min_lot = config
+            .min_sell_quantity
+            .filter(|&minimum| quantity < minimum && i64::try_from(quantity) != Ok(total_shares));
+
+        let error = match op.operation {
+            // Cancelling needs the history of operations kept by `TaxEngine`.
+            Operation::Cancel => Some(SimError::CancelNotFound {
+                id: op.note.clone().unwrap_or_default(),
+            }),
+            Operation::Sell if below_min_lot.is_some() => {
+                below_min_lot.map(|minimum| SimError::BelowMinLot { quantity, minimum })
+            }
+            Operation::Transfer if total_shares < 0 => Some(SimError::ShortTransfer { quantity }),
+            Operation::Buy if total_shares < 0 && quantity > short_shares => {
+                Some(SimError::Overcover {
+                    requested: quantity,
+                    available: short_shares,
+                })
+            }
+            Operation::Sell if total_shares == 0 && quantity > 0 && !config.allow_short => {
+                Some(SimError::NoPosition { quantity })
+            }
+            Operation::ReturnOfCapital if total_shares <= 0 => {
+                Some(SimError::NoPosition { quantity })
+            }
+            Operation::ReturnOfCapital if quantity > total_shares as usize => {
+                Some(SimError::Oversell {
+                    requested: quantity,
+                    available: total_shares as usize,
+                })
+            }
+            Operation::ReturnOfCapital if quantity < total_shares as usize => {
+                Some(SimError::PartialDistribution {
+                    quantity,
+                    held: total_shares as usize,
+                })
+            }
+            Operation::Sell if !(config.allow_short && total_shares <= 0) => {
+                let long_shares = total_shares.max(0) as usize;
+                (quantity > long_shares).then_some(SimError::Oversell {
+                    requested: quantity,
+                    available: long_shares,
+                })
+            }
+            _ => None,
+        };
+        if let Some(error) = error {
+            return Err(SimError::Operation {
+                index,
+                error: Box::new(error),
+            });
+        }
+
+        let shares = i64::try_from(quantity).ok();
+        let new_total_shares = match op.operation {
+            Operation::Buy | Operation::Transfer => {
+                shares.and_then(|shares| total_shares.checked_add(shares))
+            }
+            Operation::Sell => shares.and_then(|shares| total_shares.checked_sub(shares)),
+            Operation::ReturnOfCapital | Operation::Cancel => Some(total_shares),
+        };
+        total_shares = new_total_shares.ok_or_else(|| SimError::Operation {
+            index,
+            error: Box::new(SimError::Overflow {
+                unit_cost: op.unit_cost,
+                quantity,
+            }),
+        })?;
+    }
+    Ok(())
+}
+
+/// Measures how far the rounded weighted average drifts from the exact cost basis.
+///
+/// The weighted average is rounded to two decimals on every buy, so over many
+/// buys the stored value can drift away from the true average price paid.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+///
+/// # Returns
+///
+/// The final rounded weighted average minus the exact, un-rounded one, or the
+/// first `SimError` raised by an operation.
+pub fn audit_basis(operations: &[Op]) -> Result<f64, SimError> {
+    let (_, state) = compute_taxes_with_final_state(operations)?;
+
+    let mut total_shares = 0;
+    let mut exact_average = 0.0;
+    for op in operations {
+        match op.operation {
+            Operation::Buy | Operation::Transfer => {
+                let op = op.combine_fills()?;
+                exact_average = (total_shares as f64 * exact_average + op.total_value())
+                    / (total_shares + op.quantity) as f64;
+                total_shares += op.quantity;
+            }
+            Operation::Sell => total_shares -= op.quantity,
+            Operation::ReturnOfCapital if total_shares > 0 => {
+                exact_average = (exact_average - op.total_value() / total_shares as f64).max(0.0);
+            }
+            Operation::ReturnOfCapital | Operation::Cancel => {}
+        }
+    }
+    Ok(state.weighted_average - exact_average)
+}
+
+/// Suggests how many shares to sell at a loss to offset a pending taxable gain.
+///
+/// # Arguments
+///
+/// * `state` - The current `State` of the portfolio.
+/// * `current_price` - The hypothetical market price of the shares.
+/// * `pending_gain` - The taxable gain to be offset.
+///
+/// # Returns
+///
+/// The smallest quantity of shares whose realized loss fully offsets `pending_gain`,
+/// or `None` if selling at `current_price` is not a loss or the position is too small.
+pub fn advise_harvest(state: &State, current_price: f64, pending_gain: f64) -> Option<usize> {
+    if pending_gain <= 0.0 {
+        return Some(0);
+    }
+
+    let loss_per_share = state.weighted_average - current_price;
+    if loss_per_share <= 0.0 {
+        return None;
+    }
+
+    let quantity = (pending_gain / loss_per_share).ceil() as usize;
+    (quantity <= state.total_shares.max(0) as usize).then_some(quantity)
+}
+
+/// Computes taxes for a series of operations.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+///
+/// # Returns
+///
+/// A vector of `Tax` instances representing the computed taxes for each operation,
+/// or the first `SimError` raised by an operation.
+pub fn compute_taxes(operations: &[Op]) -> Result<Vec<Tax>, SimError> {
+    compute_taxes_with_config(operations, &SimConfig::default())
+}
+
+/// Computes taxes for a series of operations using the given tax rules.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+/// * `config` - The tax rules to apply.
+///
+/// # Returns
+///
+/// A vector of `Tax` instances representing the computed taxes for each operation,
+/// or the first `SimError` raised by an operation.
+pub fn compute_taxes_with_config(
+    operations: &[Op],
+    config: &SimConfig,
+) -> Result<Vec<Tax>, SimError> {
+    run_operations(State::default(), operations, config).map(|(taxes, _)| taxes)
+}
+
+/// Computes taxes for a series of operations and returns the final portfolio state.
+///
+/// The final state carries the remaining accumulated loss, which can be
+/// carried over to a later series of operations.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+///
+/// # Returns
+///
+/// A tuple containing the computed taxes for each operation and the final `State`,
+/// or the first `SimError` raised by an operation.
+pub fn compute_taxes_with_final_state(operations: &[Op]) -> Result<(Vec<Tax>, State), SimError> {
+    run_operations(State::default(), operations, &SimConfig::default())
+}
+
+/// Computes taxes for a series of operations, with details for each operation.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+///
+/// # Returns
+///
+/// A vector of `TaxDetail` instances for each operation, or the first `SimError`
+/// raised by an operation.
+pub fn compute_taxes_verbose(operations: &[Op]) -> Result<Vec<TaxDetail>, SimError> {
+    let config = SimConfig::default();
+    let mut state = State::default();
+    operations
+        .iter()
+        .map(|op| {
+            let (new_state, tax, outcome) =
+                apply_operation(state.clone(), op, &config, &linear_tax)?;
+            state = new_state;
+            Ok(TaxDetail {
+                tax: tax.tax,
+                loss_applied: outcome.loss_applied,
+                note: op.note.clone(),
+                state: state.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Computes the net cash movement of each operation in a series, after tax.
+///
+/// A sell yields its proceeds minus the tax paid, so an exempt sell yields its
+/// full proceeds. A return of capital likewise yields the distribution minus
+/// tax. A buy costs its total value (plus any tax when covering a short) and
+/// is reported as a negative amount. A transfer moves no cash and is reported
+/// as `0.0`.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+///
+/// # Returns
+///
+/// A vector with the net cash movement of each operation, or the first
+/// `SimError` raised by an operation.
+pub fn compute_net_proceeds(operations: &[Op]) -> Result<Vec<f64>, SimError> {
+    let taxes = compute_taxes(operations)?;
+    operations
+        .iter()
+        .zip(taxes)
+        .map(|(op, tax)| net_proceeds(op, &tax))
+        .collect()
+}
+
+/// Calculates the net cash movement of an operation after tax.
+fn net_proceeds(op: &Op, tax: &Tax) -> Result<f64, SimError> {
+    Ok(match op.operation {
+        Operation::Buy => -op.combine_fills()?.total_value() - tax.tax,
+        Operation::Sell | Operation::ReturnOfCapital => op.total_value() - tax.tax,
+        Operation::Transfer | Operation::Cancel => 0.0,
+    })
+}
+
+/// Computes the outcome of each operation in a series.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+///
+/// # Returns
+///
+/// A vector of `OpResult` instances for each operation, or the first `SimError`
+/// raised by an operation.
+pub fn compute_detailed(operations: &[Op]) -> Result<Vec<OpResult>, SimError> {
+    compute_detailed_with_config(operations, &SimConfig::default())
+}
+
+/// Computes the outcome of each operation in a series using the given tax rules.
+///
+/// With `config.convert_to_base` set, the realized gain and the proceeds are
+/// in the home currency.
+///
 /// # Arguments
 ///
 /// * `operations` - A slice of `Op` representing the series of operations.
+/// * `config` - The tax rules to apply.
 ///
 /// # Returns
 ///
-/// A vector of `Tax` instances representing the computed taxes for each operation.
-pub fn compute_taxes(operations: &[Op]) -> Vec<Tax> {
+/// A vector of `OpResult` instances for each operation, or the first `SimError`
+/// raised by an operation.
+pub fn compute_detailed_with_config(
+    operations: &[Op],
+    config: &SimConfig,
+) -> Result<Vec<OpResult>, SimError> {
+    let mut state = State::default();
     operations
         .iter()
-        .scan(State::default(), |state, op| {
-            let (new_state, tax) = handle_operation(state.clone(), op);
-            *state = new_state;
-            Some(tax)
+        .map(|op| {
+            let (new_state, tax, outcome) =
+                apply_operation(state.clone(), op, config, &linear_tax)?;
+            let op = op.prepare(config)?;
+            state = new_state;
+            Ok(OpResult {
+                realized_gain: outcome.gain,
+                loss_applied: outcome.loss_applied,
+                proceeds: net_proceeds(&op, &tax)?,
+                tax,
+                state: state.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Compares two tax computations for the same series of operations.
+///
+/// Operations are compared by position. When one series is longer, its extra
+/// operations are reported as differences, with no tax on the other side.
+///
+/// # Arguments
+///
+/// * `a` - The taxes of the first computation.
+/// * `b` - The taxes of the second computation.
+/// * `epsilon` - The largest difference still considered equal.
+///
+/// # Returns
+///
+/// The index, the tax in `a` and the tax in `b` of every operation whose
+/// taxes differ by more than `epsilon` or that only one series has.
+pub fn diff_taxes(a: &[Tax], b: &[Tax], epsilon: f64) -> Vec<(usize, Option<f64>, Option<f64>)> {
+    (0..a.len().max(b.len()))
+        .filter_map(|index| match (a.get(index), b.get(index)) {
+            (Some(a), Some(b)) if a.approx_eq(b, epsilon) => None,
+            (a, b) => Some((index, a.map(|tax| tax.tax), b.map(|tax| tax.tax))),
         })
         .collect()
 }
 
+/// Computes taxes for a series of operations using a custom tax function.
+///
+/// The accumulated loss and exemption rules are applied as usual; only the
+/// computation of the tax on the net taxable gain is replaced. A per-operation
+/// `tax_rate` still takes precedence over `tax_fn`.
+///
+/// # Arguments
+///
+/// * `operations` - A slice of `Op` representing the series of operations.
+/// * `tax_fn` - The function computing the tax from the net taxable gain and the config.
+///
+/// # Returns
+///
+/// A vector of `Tax` instances representing the computed taxes for each operation,
+/// or the first `SimError` raised by an operation.
+pub fn compute_taxes_with_fn(
+    operations: &[Op],
+    tax_fn: impl Fn(f64, &SimConfig) -> f64,
+) -> Result<Vec<Tax>, SimError> {
+    run_operations_with_fn(State::default(), operations, &SimConfig::default(), &tax_fn)
+        .map(|(taxes, _)| taxes)
+}
+
+/// A function computing the tax from the net taxable gain and the config.
+type TaxFn<'a> = dyn Fn(f64, &SimConfig) -> f64 + 'a;
+
+/// Computes the tax at a flat `config.tax_rate`.
+fn linear_tax(net_taxable_gain: f64, config: &SimConfig) -> f64 {
+    net_taxable_gain * config.tax_rate
+}
+
+fn run_operations(
+    state: State,
+    operations: &[Op],
+    config: &SimConfig,
+) -> Result<(Vec<Tax>, State), SimError> {
+    run_operations_with_fn(state, operations, config, &linear_tax)
+}
+
+fn run_operations_with_fn(
+    mut state: State,
+    operations: &[Op],
+    config: &SimConfig,
+    tax_fn: &TaxFn<'_>,
+) -> Result<(Vec<Tax>, State), SimError> {
+    let mut taxes = Vec::with_capacity(operations.len());
+    let mut index = 0;
+    while index < operations.len() {
+        if config.batch_buys && state.total_shares >= 0 {
+            let buys = operations[index..]
+                .iter()
+                .take_while(|op| matches!(op.operation, Operation::Buy))
+                .count();
+            if buys > 1 {
+                state = state.add_buys(&operations[index..index + buys], config)?;
+                taxes.extend((0..buys).map(|_| Tax::default()));
+                index += buys;
+                continue;
+            }
+        }
+
+        let (new_state, tax, _) =
+            apply_operation(state.clone(), &operations[index], config, tax_fn)?;
+        state = new_state;
+        taxes.push(tax);
+        index += 1;
+    }
+    Ok((taxes, state))
+}
+
 /// Handles a single operation and computes the resulting state and tax.
 ///
 /// # Arguments
@@ -230,139 +1595,1692 @@ pub fn compute_taxes(operations: &[Op]) -> Vec<Tax> {
 ///
 /// # Returns
 ///
-/// A tuple containing the new `State` after the operation and the `Tax` for the operation.
-pub fn handle_operation(state: State, op: &Op) -> (State, Tax) {
+/// A tuple containing the new `State` after the operation and the `Tax` for the operation,
+/// or the `SimError` raised by the operation.
+pub fn handle_operation(state: State, op: &Op) -> Result<(State, Tax), SimError> {
+    handle_operation_with_config(state, op, &SimConfig::default())
+}
+
+/// Handles a single operation under the given tax rules and computes the resulting state and tax.
+///
+/// # Arguments
+///
+/// * `state` - The current `State` of the portfolio.
+/// * `op` - The `Op` to be handled.
+/// * `config` - The tax rules to apply.
+///
+/// # Returns
+///
+/// A tuple containing the new `State` after the operation and the `Tax` for the operation,
+/// or the `SimError` raised by the operation.
+pub fn handle_operation_with_config(
+    state: State,
+    op: &Op,
+    config: &SimConfig,
+) -> Result<(State, Tax), SimError> {
+    apply_operation(state, op, config, &linear_tax).map(|(state, tax, _)| (state, tax))
+}
+
+fn apply_operation(
+    state: State,
+    op: &Op,
+    config: &SimConfig,
+    tax_fn: &TaxFn<'_>,
+) -> Result<(State, Tax, Outcome), SimError> {
+    let op = &op.prepare(config)?;
     match op.operation {
-        Operation::Buy => state.handle_buy(op),
-        Operation::Sell => state.handle_sell(op),
+        Operation::Buy => state.buy(op, config, tax_fn),
+        Operation::Sell => state.sell(op, config, tax_fn),
+        Operation::Transfer => state
+            .handle_transfer(op)
+            .map(|(state, tax)| (state, tax, Outcome::default())),
+        Operation::ReturnOfCapital => state.roc(op, config, tax_fn),
+        // Cancelling needs the history of operations kept by `TaxEngine`.
+        Operation::Cancel => Err(SimError::CancelNotFound {
+            id: op.note.clone().unwrap_or_default(),
+        }),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// An operation pushed to a `TaxEngine`, with what is needed to roll it back.
+#[derive(Debug, Clone)]
+struct Entry {
+    /// The pushed operation.
+    op: Op,
+    /// The state of the portfolio before the operation.
+    checkpoint: State,
+    /// The tax the operation contributes to the total, zero for a cancel or a
+    /// cancelled operation.
+    effective_tax: f64,
+    /// Whether the operation has been cancelled.
+    cancelled: bool,
+    /// For a cancel, the checkpoint and effective tax of every entry from the
+    /// cancelled one onwards, as they were before the cancel.
+    replaced: Vec<(State, f64)>,
+}
+
+impl Entry {
+    /// Returns whether the operation is in effect, neither cancelled nor a cancel.
+    fn is_live(&self) -> bool {
+        !self.cancelled && !matches!(self.op.operation, Operation::Cancel)
+    }
+}
+
+/// Computes taxes incrementally, one operation at a time.
+///
+/// The engine keeps a checkpoint of the portfolio state before every
+/// pushed operation, so the last operation can be rolled back with
+/// [`TaxEngine::undo`] without replaying the whole history. With
+/// [`TaxEngine::with_history_limit`], only the most recent operations are
+/// kept, and older ones can no longer be undone or cancelled.
+#[derive(Debug, Default)]
+pub struct TaxEngine {
+    /// The tax rules applied to every pushed operation.
+    config: SimConfig,
+    /// The current state of the portfolio.
+    state: State,
+    /// The retained operations, in push order.
+    entries: Vec<Entry>,
+    /// The tax computed for each retained operation, in push order.
+    taxes: Vec<Tax>,
+    /// The maximum number of operations retained, if limited.
+    history_limit: Option<usize>,
+}
+
+impl TaxEngine {
+    /// Creates a new `TaxEngine` with an empty portfolio.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new `TaxEngine` with an empty portfolio and the given tax rules.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The tax rules to apply.
+    pub fn with_config(config: SimConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Limits the number of operations the engine retains.
+    ///
+    /// Once more than `limit` operations have been pushed, the oldest are
+    /// dropped, so memory stays bounded on long feeds.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of operations to retain.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is `0`, since the last pushed operation is always kept.
+    pub fn with_history_limit(mut self, limit: usize) -> Self {
+        assert!(limit > 0, "the history limit must be at least 1");
+        self.history_limit = Some(limit);
+        self
+    }
+
+    /// Applies an operation to the portfolio.
+    ///
+    /// A cancel operation recomputes the portfolio as if the operation in
+    /// effect with the same note had never happened, replaying the operations
+    /// pushed after it. Its tax is the change in the total tax, negative when
+    /// tax paid earlier is reversed. Notes must be unique among the retained
+    /// operations in effect, so that a cancel always refers to a single
+    /// operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The `Op` to be applied.
+    ///
+    /// # Returns
+    ///
+    /// The `Tax` computed for the operation, or the `SimError` raised by it,
+    /// in which case the engine is left unchanged. An operation reusing the
+    /// note of an operation in effect fails with `SimError::DuplicateNote`.
+    pub fn push(&mut self, op: &Op) -> Result<&Tax, SimError> {
+        self.apply(op)?;
+        Ok(self.taxes.last().expect("tax was just pushed"))
+    }
+
+    /// Applies an operation to the portfolio, like [`TaxEngine::push`], and
+    /// details how its tax was reached.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The `Op` to be applied.
+    ///
+    /// # Returns
+    ///
+    /// The `TaxDetail` of the operation, or the `SimError` raised by it, in
+    /// which case the engine is left unchanged.
+    pub fn push_verbose(&mut self, op: &Op) -> Result<TaxDetail, SimError> {
+        let outcome = self.apply(op)?;
+        Ok(TaxDetail {
+            tax: self.taxes.last().expect("tax was just pushed").tax,
+            loss_applied: outcome.loss_applied,
+            note: op.note.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    /// Applies an operation to the portfolio and records it in the history.
+    fn apply(&mut self, op: &Op) -> Result<Outcome, SimError> {
+        let (tax, outcome) = if matches!(op.operation, Operation::Cancel) {
+            (self.cancel(op)?, Outcome::default())
+        } else {
+            if op.note.is_some() && self.find_live(op.note.as_ref()).is_some() {
+                return Err(SimError::DuplicateNote {
+                    id: op.note.clone().unwrap_or_default(),
+                });
+            }
+
+            let (new_state, tax, outcome) =
+                apply_operation(self.state.clone(), op, &self.config, &linear_tax)?;
+            self.entries.push(Entry {
+                op: op.clone(),
+                checkpoint: std::mem::replace(&mut self.state, new_state),
+                effective_tax: tax.tax,
+                cancelled: false,
+                replaced: Vec::new(),
+            });
+            (tax, outcome)
+        };
+        self.taxes.push(tax);
+
+        if let Some(limit) = self.history_limit {
+            let excess = self.entries.len().saturating_sub(limit);
+            self.entries.drain(..excess);
+            self.taxes.drain(..excess);
+        }
+        Ok(outcome)
+    }
+
+    /// Returns the position of the retained operation in effect with the given note.
+    fn find_live(&self, note: Option<&String>) -> Option<usize> {
+        note?;
+        self.entries
+            .iter()
+            .rposition(|entry| entry.is_live() && entry.op.note.as_ref() == note)
+    }
+
+    /// Cancels the operation in effect whose note matches the note of `cancel`,
+    /// and records the cancel.
+    ///
+    /// Only the operations pushed after the cancelled one are replayed,
+    /// starting from the checkpoint before it.
+    ///
+    /// # Returns
+    ///
+    /// The change in the total tax, or the `SimError` raised by the replay, in
+    /// which case the engine is left unchanged.
+    fn cancel(&mut self, cancel: &Op) -> Result<Tax, SimError> {
+        let target =
+            self.find_live(cancel.note.as_ref())
+                .ok_or_else(|| SimError::CancelNotFound {
+                    id: cancel.note.clone().unwrap_or_default(),
+                })?;
+
+        let mut state = self.entries[target].checkpoint.clone();
+        let mut replayed = vec![(state.clone(), 0.0)];
+        for entry in &self.entries[target + 1..] {
+            let checkpoint = state.clone();
+            let mut effective_tax = 0.0;
+            if entry.is_live() {
+                let (new_state, tax) =
+                    handle_operation_with_config(state, &entry.op, &self.config)?;
+                state = new_state;
+                effective_tax = tax.tax;
+            }
+            replayed.push((checkpoint, effective_tax));
+        }
+
+        let paid: f64 = self.entries[target..]
+            .iter()
+            .map(|entry| entry.effective_tax)
+            .sum();
+        let owed: f64 = replayed.iter().map(|(_, tax)| tax).sum();
+        let replaced = self.entries[target..]
+            .iter()
+            .map(|entry| (entry.checkpoint.clone(), entry.effective_tax))
+            .collect();
+
+        self.entries[target].cancelled = true;
+        for (entry, (checkpoint, effective_tax)) in self.entries[target..].iter_mut().zip(replayed)
+        {
+            entry.checkpoint = checkpoint;
+            entry.effective_tax = effective_tax;
+        }
+        self.entries.push(Entry {
+            op: cancel.clone(),
+            checkpoint: std::mem::replace(&mut self.state, state),
+            effective_tax: 0.0,
+            cancelled: false,
+            replaced,
+        });
+        Ok(Tax::new(owed - paid))
+    }
+
+    /// Rolls back the last pushed operation.
+    ///
+    /// # Returns
+    ///
+    /// The `Tax` of the undone operation, or `None` if there is nothing to undo,
+    /// either because nothing was pushed or because the history limit was reached.
+    pub fn undo(&mut self) -> Option<Tax> {
+        let entry = self.entries.pop()?;
+        self.state = entry.checkpoint;
+
+        // Restore the entries replayed by a cancel, as far as they are still retained.
+        let restored = entry.replaced.len().min(self.entries.len());
+        let start = self.entries.len() - restored;
+        for (retained, (checkpoint, effective_tax)) in self.entries[start..]
+            .iter_mut()
+            .zip(&entry.replaced[entry.replaced.len() - restored..])
+        {
+            retained.checkpoint = checkpoint.clone();
+            retained.effective_tax = *effective_tax;
+        }
+        if restored > 0 && restored == entry.replaced.len() {
+            self.entries[start].cancelled = false;
+        }
+
+        self.taxes.pop()
+    }
+
+    /// Returns the current state of the portfolio.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Returns the taxes computed for the retained operations, one per pushed operation.
+    pub fn taxes(&self) -> &[Tax] {
+        &self.taxes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that the taxes match the expected amounts to within a cent.
+    fn assert_taxes_approx(taxes: &[Tax], expected: &[f64]) {
+        assert_eq!(taxes.len(), expected.len(), "{:?} != {:?}", taxes, expected);
+        for (tax, amount) in taxes.iter().zip(expected) {
+            assert!(
+                tax.approx_eq(&Tax::new(*amount), 0.01),
+                "{:?} != {:?}",
+                taxes,
+                expected
+            );
+        }
+    }
+
+    fn create_op(operation: &str, unit_cost: f64, quantity: usize) -> Op {
+        Op::new(operation.into(), unit_cost, quantity)
+    }
+
+    fn create_op_with_note(operation: &str, unit_cost: f64, quantity: usize, note: &str) -> Op {
+        Op {
+            note: Some(note.to_string()),
+            ..create_op(operation, unit_cost, quantity)
+        }
+    }
+
+    #[test]
+    fn test_calculate_weighted_average() {
+        // Test case 1: Simple case
+        assert_eq!(calculate_weighted_average(100, 10.0, 50, 20.0), 13.33);
+
+        // Test case 2: No existing shares
+        assert_eq!(calculate_weighted_average(0, 0.0, 100, 15.0), 15.00);
+
+        // Test case 3: No new shares
+        assert_eq!(calculate_weighted_average(100, 10.0, 0, 0.0), 10.00);
+
+        // Test case 4: Large numbers
+        assert_eq!(
+            calculate_weighted_average(1_000_000, 50.0, 500_000, 60.0),
+            53.33
+        );
+
+        // Test case 5: Fractional prices
+        assert_eq!(calculate_weighted_average(200, 15.75, 100, 16.25), 15.92);
+
+        // Test case 6: No shares at all
+        assert_eq!(calculate_weighted_average(0, 0.0, 0, 10.0), 0.00);
+    }
+
+    #[test]
+    fn test_zero_quantity_buy_on_empty_position() {
+        let ops = vec![
+            create_op("buy", 10.00, 0),
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 50.00, 10000),
+        ];
+        assert_eq!(compute_taxes(&ops).unwrap(), vec![0.00, 0.00, 80000.00]);
+        let batched = SimConfig {
+            batch_buys: true,
+            ..SimConfig::default()
+        };
+        assert_eq!(
+            compute_taxes_with_config(&ops, &batched).unwrap(),
+            vec![0.00, 0.00, 80000.00]
+        );
+
+        // The state after the empty buy still round-trips.
+        let (_, state) = compute_taxes_with_final_state(&ops[..1]).unwrap();
+        assert_eq!(state, State::default());
+        assert_eq!(load_state(&save_state(&state)).unwrap(), state);
+
+        // A weighted average that cannot be represented is rejected.
+        let buy = create_op("buy", f64::MAX, 1);
+        assert_eq!(
+            State::new(2, f64::MAX, 0.0).handle_buy(&buy, &SimConfig::default()),
+            Err(SimError::Overflow {
+                unit_cost: f64::MAX,
+                quantity: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_case_1() {
+        let ops = vec![
+            create_op("buy", 10.00, 100),
+            create_op("sell", 15.00, 50),
+            create_op("sell", 15.00, 50),
+        ];
+        let result = compute_taxes(&ops).unwrap();
+        assert_taxes_approx(&result, &[0.00, 0.00, 0.00]);
+    }
+
+    #[test]
+    fn test_case_2() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 20.00, 5000),
+            create_op("sell", 5.00, 5000),
+        ];
+        let result = compute_taxes(&ops).unwrap();
+        assert_taxes_approx(&result, &[0.00, 10000.00, 0.00]);
+    }
+
+    #[test]
+    fn test_case_1_and2() {
+        let cases = [
+            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell", "unit-cost":15.00, "quantity": 50},{"operation":"sell", "unit-cost":15.00, "quantity": 50}]"#,
+            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":20.00, "quantity": 5000}, {"operation":"sell", "unit-cost":5.00, "quantity": 5000}]"#,
+        ];
+
+        let results: Vec<Vec<Tax>> = cases
+            .iter()
+            .map(|case| {
+                let ops: Vec<Op> = serde_json::from_str(case).expect("Invalid JSON");
+                compute_taxes(&ops).unwrap()
+            })
+            .collect();
+
+        assert_eq!(
+            results,
+            vec![vec![0.00, 0.00, 0.00], vec![0.00, 10000.00, 0.00]]
+        );
+    }
+
+    #[test]
+    fn test_case_3() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 5.00, 5000),
+            create_op("sell", 20.00, 3000),
+        ];
+        let result = compute_taxes(&ops).unwrap();
+        assert_taxes_approx(&result, &[0.00, 0.00, 1000.00]);
+    }
+
+    #[test]
+    fn test_case_4() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("buy", 25.00, 5000),
+            create_op("sell", 15.00, 10000),
+        ];
+        let result = compute_taxes(&ops).unwrap();
+        assert_taxes_approx(&result, &[0.00, 0.00, 0.00]);
+    }
+
+    #[test]
+    fn test_case_5() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("buy", 25.00, 5000),
+            create_op("sell", 15.00, 10000),
+            create_op("sell", 25.00, 5000),
+        ];
+        let result = compute_taxes(&ops).unwrap();
+        assert_taxes_approx(&result, &[0.00, 0.00, 0.00, 10000.00]);
+    }
+
+    #[test]
+    fn test_case_6() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 2.00, 5000),
+            create_op("sell", 20.00, 2000),
+            create_op("sell", 20.00, 2000),
+            create_op("sell", 25.00, 1000),
+        ];
+        let result = compute_taxes(&ops).unwrap();
+        assert_taxes_approx(&result, &[0.00, 0.00, 0.00, 0.00, 3000.00]);
+    }
+
+    #[test]
+    fn test_case_7() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 2.00, 5000),
+            create_op("sell", 20.00, 2000),
+            create_op("sell", 20.00, 2000),
+            create_op("sell", 25.00, 1000),
+            create_op("buy", 20.00, 10000),
+            create_op("sell", 15.00, 5000),
+            create_op("sell", 30.00, 4350),
+            create_op("sell", 30.00, 650),
+        ];
+        let result = compute_taxes(&ops).unwrap();
+        assert_taxes_approx(
+            &result,
+            &[0.00, 0.00, 0.00, 0.00, 3000.00, 0.00, 0.00, 3700.00, 0.00],
+        );
+    }
+
+    #[test]
+    fn test_case_8() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 50.00, 10000),
+            create_op("buy", 20.00, 10000),
+            create_op("sell", 50.00, 10000),
+        ];
+        let result = compute_taxes(&ops).unwrap();
+        assert_taxes_approx(&result, &[0.00, 80000.00, 0.00, 60000.00]);
+    }
+
+    #[test]
+    fn test_engine_undo() {
+        let mut engine = TaxEngine::new();
+        engine.push(&create_op("buy", 10.00, 10000)).unwrap();
+        engine.push(&create_op("sell", 20.00, 5000)).unwrap();
+        engine.push(&create_op("sell", 5.00, 5000)).unwrap();
+
+        assert_eq!(engine.undo(), Some(Tax::new(0.00)));
+        engine.push(&create_op("sell", 25.00, 5000)).unwrap();
+
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 20.00, 5000),
+            create_op("sell", 25.00, 5000),
+        ];
+        let mut expected = State::default();
+        for op in &ops {
+            expected = handle_operation(expected, op).unwrap().0;
+        }
+        assert_eq!(engine.taxes(), compute_taxes(&ops).unwrap().as_slice());
+        assert_eq!(engine.state(), &expected);
+    }
+
+    #[test]
+    fn test_engine_undo_empty() {
+        let mut engine = TaxEngine::new();
+        assert_eq!(engine.undo(), None);
+        assert_eq!(engine.state(), &State::default());
+    }
+
+    #[test]
+    fn test_min_tax_waives_small_taxes() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 10.02, 2001),
+        ];
+        assert_eq!(compute_taxes(&ops).unwrap(), vec![0.00, 8.00]);
+
+        let config = SimConfig {
+            min_tax: 10.00,
+            ..SimConfig::default()
+        };
+        let result = compute_taxes_with_config(&ops, &config).unwrap();
+        assert_eq!(result, vec![0.00, 0.00]);
+
+        // A credit returned by a custom tax function is not waived.
+        let result = compute_taxes_with_fn(&ops, |gain, _| -gain * 0.1).unwrap();
+        assert_eq!(result, vec![0.00, -4.00]);
+    }
+
+    #[test]
+    fn test_op_serialization_round_trip() {
+        let json = r#"{"operation":"sell","unit-cost":15.0,"quantity":50}"#;
+        let op: Op = serde_json::from_str(json).expect("Invalid JSON");
+        assert_eq!(serde_json::to_string(&op).unwrap(), json);
+    }
+
+    #[test]
+    fn test_advise_harvest() {
+        let state = State::new(1000, 20.00, 0.0);
+
+        // Each share sold at 15.00 realizes a loss of 5.00.
+        assert_eq!(advise_harvest(&state, 15.00, 1000.00), Some(200));
+        assert_eq!(advise_harvest(&state, 15.00, 1001.00), Some(201));
+
+        // Selling the whole position only realizes a loss of 5000.00.
+        assert_eq!(advise_harvest(&state, 15.00, 6000.00), None);
+
+        // Selling above the weighted average never realizes a loss.
+        assert_eq!(advise_harvest(&state, 25.00, 1000.00), None);
+    }
+
+    #[test]
+    fn test_max_loss_carryforward() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 2.00, 5000),
+            create_op("sell", 20.00, 5000),
+        ];
+        assert_eq!(compute_taxes(&ops).unwrap(), vec![0.00, 0.00, 2000.00]);
+
+        let config = SimConfig {
+            max_loss_carryforward: Some(10000.00),
+            ..SimConfig::default()
+        };
+        let result = compute_taxes_with_config(&ops, &config).unwrap();
+        assert_eq!(result, vec![0.00, 0.00, 8000.00]);
+    }
+
+    #[test]
+    fn test_oversell_is_rejected() {
+        let ops = vec![
+            create_op("buy", 10.00, 100),
+            create_op("sell", 15.00, 50),
+            create_op("sell", 15.00, 60),
+        ];
+        assert_eq!(
+            compute_taxes(&ops),
+            Err(SimError::Oversell {
+                requested: 60,
+                available: 50,
+            })
+        );
+    }
+
+    #[test]
+    fn test_short_then_cover_gain() {
+        let ops = vec![
+            create_op("sell", 20.00, 10000),
+            create_op("buy", 10.00, 10000),
+        ];
+        assert_eq!(
+            compute_taxes(&ops),
+            Err(SimError::NoPosition { quantity: 10000 })
+        );
+
+        let config = SimConfig {
+            allow_short: true,
+            ..SimConfig::default()
+        };
+        let result = compute_taxes_with_config(&ops, &config).unwrap();
+        assert_eq!(result, vec![0.00, 20000.00]);
+    }
+
+    #[test]
+    fn test_small_cover_is_not_exempt() {
+        let config = SimConfig {
+            allow_short: true,
+            ..SimConfig::default()
+        };
+        // Both the short sale and the cover are worth less than the threshold.
+        let ops = vec![create_op("sell", 20.00, 500), create_op("buy", 10.00, 500)];
+        let result = compute_taxes_with_config(&ops, &config).unwrap();
+        assert_eq!(result, vec![0.00, 1000.00]);
+    }
+
+    #[test]
+    fn test_short_then_cover_loss() {
+        let config = SimConfig {
+            allow_short: true,
+            ..SimConfig::default()
+        };
+        let mut state = State::default();
+        for op in [
+            create_op("sell", 10.00, 1000),
+            create_op("sell", 12.00, 1000),
+            create_op("buy", 16.00, 2000),
+        ] {
+            let (new_state, tax) = handle_operation_with_config(state, &op, &config).unwrap();
+            assert_eq!(tax, 0.00);
+            state = new_state;
+        }
+        assert_eq!(state, State::new(0, 11.00, 10000.00));
+    }
+
+    #[test]
+    fn test_overcover_is_rejected() {
+        let config = SimConfig {
+            allow_short: true,
+            ..SimConfig::default()
+        };
+        let ops = vec![create_op("sell", 10.00, 100), create_op("buy", 8.00, 150)];
+        assert_eq!(
+            compute_taxes_with_config(&ops, &config),
+            Err(SimError::Overcover {
+                requested: 150,
+                available: 100,
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_states() {
+        let a = State::new(100, 10.00, 500.00);
+        let b = State::new(50, 20.00, 250.00);
+        assert_eq!(a.merge(&b), Ok(State::new(150, 13.33, 750.00)));
+        assert_eq!(b.merge(&a), Ok(State::new(150, 13.33, 750.00)));
+        assert_eq!(
+            State::default().merge(&State::default()),
+            Ok(State::default())
+        );
+
+        let short = State::new(-50, 20.00, 0.0);
+        assert_eq!(
+            short.merge(&State::new(-50, 10.00, 0.0)),
+            Ok(State::new(-100, 15.00, 0.0))
+        );
+        assert_eq!(State::default().merge(&short), Ok(short.clone()));
+        let error = a.merge(&short).unwrap_err();
+        assert_eq!(
+            error,
+            SimError::MixedMerge {
+                shares: 100,
+                other_shares: -50,
+            }
+        );
+        assert_eq!(error.kind(), "mixed_merge");
+    }
+
+    #[test]
+    fn test_stable_arithmetic_keeps_whole_cents() {
+        // A simple linear congruential generator keeps the batch deterministic.
+        let mut seed: u64 = 42;
+        let mut next = move |max: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) % max
+        };
+
+        let mut ops = Vec::new();
+        for _ in 0..1_000 {
+            let quantity = next(5000) as usize + 1;
+            ops.push(create_op(
+                "buy",
+                next(10_000) as f64 / 100.0 + 0.01,
+                quantity,
+            ));
+            ops.push(create_op(
+                "sell",
+                next(10_000) as f64 / 1000.0 + 0.001,
+                quantity,
+            ));
+        }
+
+        let whole_cents = |config: &SimConfig| {
+            let mut state = State::default();
+            ops.iter().all(|op| {
+                state = handle_operation_with_config(state.clone(), op, config)
+                    .unwrap()
+                    .0;
+                state.accumulated_loss == round_cents(state.accumulated_loss)
+            })
+        };
+        let config = SimConfig {
+            stable_arithmetic: true,
+            ..SimConfig::default()
+        };
+        assert!(whole_cents(&config));
+        assert!(!whole_cents(&SimConfig::default()));
+
+        let ops = vec![create_op("buy", 10.00, 1000), create_op("sell", 9.99, 3)];
+        let (_, state) = run_operations(State::default(), &ops, &config).unwrap();
+        assert_eq!(state.accumulated_loss, 0.03);
+        let (_, state) = run_operations(State::default(), &ops, &SimConfig::default()).unwrap();
+        assert_ne!(state.accumulated_loss, 0.03);
+    }
+
+    #[test]
+    fn test_stable_arithmetic_is_reproducible() {
+        // A simple linear congruential generator keeps the batch deterministic.
+        let mut seed: u64 = 42;
+        let mut next = move |max: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) % max
+        };
+
+        let mut ops = Vec::new();
+        for _ in 0..10_000 {
+            let quantity = next(5000) as usize + 1;
+            ops.push(create_op(
+                "buy",
+                next(10_000) as f64 / 100.0 + 0.01,
+                quantity,
+            ));
+            ops.push(create_op(
+                "sell",
+                next(10_000) as f64 / 100.0 + 0.01,
+                quantity,
+            ));
+        }
+
+        let config = SimConfig {
+            stable_arithmetic: true,
+            ..SimConfig::default()
+        };
+        let first = compute_taxes_with_config(&ops, &config).unwrap();
+        let second = compute_taxes_with_config(&ops, &config).unwrap();
+        let bits = |taxes: &[Tax]| taxes.iter().map(|t| t.tax.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&first), bits(&second));
+        assert!(first.iter().any(|tax| tax.tax > 0.0));
+    }
+
+    #[test]
+    fn test_stable_arithmetic_is_independent_of_summation_order() {
+        let losses = [
+            create_op("sell", 0.01, 1),
+            create_op("sell", 0.02, 1),
+            create_op("sell", 0.08, 1),
+        ];
+        let accumulated_loss = |order: [usize; 3], config: &SimConfig| {
+            let mut ops = vec![create_op("buy", 1.00, 1000)];
+            ops.extend(order.iter().map(|&index| losses[index].clone()));
+            let (_, state) = run_operations(State::default(), &ops, config).unwrap();
+            state.accumulated_loss.to_bits()
+        };
+
+        let config = SimConfig {
+            stable_arithmetic: true,
+            ..SimConfig::default()
+        };
+        assert_eq!(accumulated_loss([0, 1, 2], &config), 2.89f64.to_bits());
+        assert_eq!(accumulated_loss([2, 1, 0], &config), 2.89f64.to_bits());
+        let default = SimConfig::default();
+        assert_ne!(
+            accumulated_loss([0, 1, 2], &default),
+            accumulated_loss([2, 1, 0], &default)
+        );
+    }
+
+    #[test]
+    fn test_transfer_blends_basis() {
+        let ops = vec![
+            create_op("buy", 10.00, 100),
+            create_op("transfer", 30.00, 100),
+            create_op("sell", 120.00, 200),
+        ];
+        let mut state = State::default();
+        for op in &ops[..2] {
+            state = handle_operation(state, op).unwrap().0;
+        }
+        assert_eq!(state, State::new(200, 20.00, 0.0));
+
+        let result = compute_taxes(&ops).unwrap();
+        assert_eq!(result, vec![0.00, 0.00, 4000.00]);
+    }
+
+    #[test]
+    fn test_parse_ops_reports_column() {
+        let line =
+            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell""#;
+        let error = parse_ops(line).unwrap_err();
+        assert_eq!(error.line, 1);
+        assert_eq!(error.column, line.len());
+        assert!(error
+            .to_string()
+            .ends_with(&format!("column {}", line.len())));
+
+        let ops = parse_ops(r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}]"#);
+        assert_eq!(ops.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_tax_rate_override() {
+        let json = r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":20.00, "quantity": 2500, "tax-rate": 0.15}, {"operation":"sell", "unit-cost":20.00, "quantity": 2500}]"#;
+        let ops: Vec<Op> = serde_json::from_str(json).expect("Invalid JSON");
+        assert_eq!(ops[1].tax_rate, Some(0.15));
+        assert_eq!(ops[2].tax_rate, None);
+
+        let result = compute_taxes(&ops).unwrap();
+        assert_eq!(result, vec![0.00, 3750.00, 5000.00]);
+    }
+
+    #[test]
+    fn test_market_value_and_unrealized_gain() {
+        let state = State::new(100, 10.00, 0.0);
+        assert_eq!(state.market_value(15.00), 1500.00);
+        assert_eq!(state.unrealized_gain(15.00), 500.00);
+        assert_eq!(state.unrealized_gain(7.50), -250.00);
+
+        let short = State::new(-100, 10.00, 0.0);
+        assert_eq!(short.market_value(15.00), -1500.00);
+        assert_eq!(short.unrealized_gain(15.00), -500.00);
+    }
+
+    #[test]
+    fn test_compute_all_batches() {
+        let document = r#"[
+            [{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell", "unit-cost":15.00, "quantity": 50},{"operation":"sell", "unit-cost":15.00, "quantity": 50}],
+            [{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":20.00, "quantity": 5000}, {"operation":"sell", "unit-cost":5.00, "quantity": 5000}]
+        ]"#;
+        let result = compute_all_batches(document.as_bytes()).unwrap();
+
+        let batches: Vec<Vec<Op>> = serde_json::from_str(document).expect("Invalid JSON");
+        let expected: Vec<Vec<Tax>> = batches
+            .iter()
+            .map(|batch| compute_taxes(batch).unwrap())
+            .collect();
+        assert_eq!(result, expected);
+
+        let error = compute_all_batches("[[".as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), "parse");
+    }
+
+    #[test]
+    fn test_compute_taxes_with_final_state() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 2.00, 5000),
+            create_op("sell", 20.00, 2000),
+            create_op("sell", 20.00, 2000),
+            create_op("sell", 25.00, 1000),
+        ];
+
+        // 40000.00 of loss, with 20000.00 offset by the first profitable sell.
+        let (taxes, state) = compute_taxes_with_final_state(&ops[..3]).unwrap();
+        assert_eq!(taxes, vec![0.00, 0.00, 0.00]);
+        assert_eq!(state.accumulated_loss, 20000.00);
+
+        let (taxes, state) = compute_taxes_with_final_state(&ops).unwrap();
+        assert_eq!(taxes, compute_taxes(&ops).unwrap());
+        assert_eq!(state, State::new(0, 10.00, 0.00));
+    }
+
+    #[test]
+    fn test_exempt_gains_consume_loss() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 5.00, 5000),
+            create_op("sell", 15.00, 1000),
+            create_op("sell", 20.00, 3000),
+        ];
+        assert_eq!(
+            compute_taxes(&ops).unwrap(),
+            vec![0.00, 0.00, 0.00, 1000.00]
+        );
+
+        let config = SimConfig {
+            exempt_gains_consume_loss: true,
+            ..SimConfig::default()
+        };
+        let result = compute_taxes_with_config(&ops, &config).unwrap();
+        assert_eq!(result, vec![0.00, 0.00, 0.00, 2000.00]);
+    }
+
+    #[test]
+    fn test_buy_with_fills() {
+        let json = r#"{"operation":"buy", "unit-cost":10.00, "quantity": 400, "fills": [[10.00, 100], [20.00, 100], [15.00, 200]]}"#;
+        let filled: Op = serde_json::from_str(json).expect("Invalid JSON");
+        let state = State::new(100, 5.00, 0.0);
+        let (filled_state, tax) = state.handle_buy(&filled, &SimConfig::default()).unwrap();
+        assert_eq!(tax, 0.00);
+
+        let mut expected = state;
+        for op in [
+            create_op("buy", 10.00, 100),
+            create_op("buy", 20.00, 100),
+            create_op("buy", 15.00, 200),
+        ] {
+            expected = handle_operation(expected, &op).unwrap().0;
+        }
+        assert_eq!(filled_state, expected);
+        assert_eq!(filled_state, State::new(500, 13.00, 0.0));
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let line = r#"[{"operation":"buy", "unit-cost":10.00, "quantiy": 100}]"#;
+        let error = parse_ops(line).unwrap_err();
+        assert!(error.message.starts_with("unknown field `quantiy`"));
+        assert_eq!(error.column, 49);
+    }
+
+    #[test]
+    fn test_break_even_price() {
+        let config = SimConfig::default();
+
+        // Without accumulated loss, any profit on a large position is taxed.
+        assert_eq!(
+            State::new(10000, 10.00, 0.0).break_even_price(&config),
+            10.00
+        );
+
+        // The accumulated loss covers a profit of up to 2.00 per share.
+        let state = State::new(10000, 10.00, 20000.00);
+        assert_eq!(state.break_even_price(&config), 12.00);
+        let sell = create_op("sell", 12.00, 10000);
+        assert_eq!(state.handle_sell(&sell, &config).unwrap().1, 0.00);
+
+        // Selling 100 shares is exempt up to 200.00 per share.
+        let state = State::new(100, 10.00, 0.0);
+        assert_eq!(state.break_even_price(&config), 200.00);
+        let sell = create_op("sell", 200.00, 100);
+        assert_eq!(state.handle_sell(&sell, &config).unwrap().1, 0.00);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_weighted_average_aliases() {
+        let state = State::new(100, 10.00, 0.0);
+        assert_eq!(state.weighted_avarage(), state.weighted_average);
+        assert_eq!(
+            calculate_weighted_avarage(100, 10.0, 50, 20.0),
+            calculate_weighted_average(100, 10.0, 50, 20.0)
+        );
+    }
+
+    #[test]
+    fn test_convert_to_base_currency() {
+        let json = r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000, "fx-rate": 1.0}, {"operation":"sell", "unit-cost":10.00, "quantity": 5000, "fx-rate": 1.5}]"#;
+        let ops: Vec<Op> = serde_json::from_str(json).expect("Invalid JSON");
+        assert_eq!(compute_taxes(&ops).unwrap(), vec![0.00, 0.00]);
+
+        let config = SimConfig {
+            convert_to_base: true,
+            ..SimConfig::default()
+        };
+        let result = compute_taxes_with_config(&ops, &config).unwrap();
+        assert_eq!(result, vec![0.00, 5000.00]);
+    }
+
+    #[test]
+    fn test_tax_rounds_half_away_from_zero() {
+        let config = SimConfig {
+            threshold: 0.0,
+            ..SimConfig::default()
+        };
+        let state = State::new(100, 10.00, 0.0);
+
+        // A net profit of 12.50 lands exactly on a tax of 2.50.
+        let sell = create_op("sell", 10.50, 25);
+        assert_eq!(state.handle_sell(&sell, &config).unwrap().1, 3.00);
+
+        // A net profit of 2.50 lands exactly on a tax of 0.50.
+        let sell = create_op("sell", 10.50, 5);
+        assert_eq!(state.handle_sell(&sell, &config).unwrap().1, 1.00);
+    }
+
+    #[test]
+    fn test_apply_batch_carries_loss_across_batches() {
+        let (state, taxes) = State::default()
+            .apply_batch(&[
+                create_op("buy", 10.00, 10000),
+                create_op("sell", 5.00, 5000),
+            ])
+            .unwrap();
+        assert_eq!(taxes, vec![0.00, 0.00]);
+        assert_eq!(state, State::new(5000, 10.00, 25000.00));
+
+        let (state, taxes) = state
+            .apply_batch(&[create_op("sell", 20.00, 5000)])
+            .unwrap();
+        assert_eq!(taxes, vec![5000.00]);
+        assert_eq!(state, State::new(0, 10.00, 0.00));
+    }
+
+    #[test]
+    fn test_compute_net_proceeds() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 20.00, 5000),
+            create_op("sell", 5.00, 5000),
+        ];
+        let result = compute_net_proceeds(&ops).unwrap();
+        assert_eq!(result, vec![-100000.00, 90000.00, 25000.00]);
+
+        // Exempt sells keep their full proceeds.
+        let ops = vec![create_op("buy", 10.00, 100), create_op("sell", 15.00, 50)];
+        let result = compute_net_proceeds(&ops).unwrap();
+        assert_eq!(result, vec![-1000.00, 750.00]);
+    }
+
+    #[test]
+    fn test_tax_to_cents() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 50.00, 10000),
+            create_op("buy", 20.00, 10000),
+            create_op("sell", 50.00, 10000),
+        ];
+        let cents: Vec<i64> = compute_taxes(&ops)
+            .unwrap()
+            .iter()
+            .map(Tax::to_cents)
+            .collect();
+        assert_eq!(cents, vec![0, 8_000_000, 0, 6_000_000]);
+
+        assert_eq!(Tax::new(0.125).to_cents(), 13);
+        assert_eq!(Tax::new(1e300).to_cents(), i64::MAX);
+    }
+
+    #[test]
+    fn test_compute_taxes_with_progressive_fn() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 20.00, 5000),
+            create_op("sell", 11.00, 5000),
+        ];
+        let brackets =
+            |gain: f64, _: &SimConfig| gain.min(10000.0) * 0.1 + (gain - 10000.0).max(0.0) * 0.25;
+        let result = compute_taxes_with_fn(&ops, brackets).unwrap();
+        assert_eq!(result, vec![0.00, 11000.00, 500.00]);
+
+        let linear = compute_taxes_with_fn(&ops, |gain, config| gain * config.tax_rate).unwrap();
+        assert_eq!(linear, compute_taxes(&ops).unwrap());
+    }
+
+    #[test]
+    fn test_can_execute() {
+        let ops = vec![
+            create_op("buy", 10.00, 100),
+            create_op("sell", 15.00, 50),
+            create_op("buy", 10.00, 25),
+            create_op("sell", 15.00, 75),
+        ];
+        assert_eq!(can_execute(&ops), Ok(()));
+
+        let ops = vec![
+            create_op("buy", 10.00, 100),
+            create_op("sell", 15.00, 50),
+            create_op("sell", 15.00, 60),
+        ];
+        let error = can_execute(&ops).unwrap_err();
+        assert_eq!(
+            error,
+            SimError::Operation {
+                index: 2,
+                error: Box::new(SimError::Oversell {
+                    requested: 60,
+                    available: 50,
+                }),
+            }
+        );
+        assert_eq!(error.kind(), "oversell");
+
+        let config = SimConfig {
+            allow_short: true,
+            ..SimConfig::default()
+        };
+        let ops = vec![create_op("sell", 15.00, 100), create_op("buy", 10.00, 100)];
+        assert!(can_execute(&ops).is_err());
+        assert_eq!(can_execute_with_config(&ops, &config), Ok(()));
+        assert!(compute_taxes_with_config(&ops, &config).is_ok());
+    }
+
+    #[test]
+    fn test_simulate_sell_matches_handle_sell() {
+        let config = SimConfig::default();
+        let state = State::new(10000, 10.00, 5000.00);
+        for (unit_cost, quantity) in [(15.00, 1000), (20.00, 3000), (5.00, 3000), (10.00, 20000)] {
+            let simulated = state.simulate_sell(unit_cost, quantity, &config);
+            let actual = state
+                .handle_sell(&create_op("sell", unit_cost, quantity), &config)
+                .map(|(_, tax)| tax);
+            assert_eq!(simulated, actual);
+        }
+        assert_eq!(
+            state.simulate_sell(20.00, 3000, &config),
+            Ok(Tax::new(5000.00))
+        );
+        assert_eq!(state, State::new(10000, 10.00, 5000.00));
+    }
+
+    #[test]
+    fn test_audit_basis_detects_drift() {
+        let mut ops = vec![create_op("buy", 10.00, 100)];
+        // Each buy moves the exact average by less than half a cent, so the
+        // rounded average never moves.
+        ops.extend((0..100).map(|_| create_op("buy", 10.01, 1)));
+
+        let drift = audit_basis(&ops).unwrap();
+        assert!((drift + 0.005).abs() < 1e-9);
+
+        let ops = vec![create_op("buy", 10.00, 100), create_op("buy", 20.00, 100)];
+        assert_eq!(audit_basis(&ops).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_sell_at_weighted_average() {
+        let state = State::new(10000, 10.00, 1000.00);
+        let sell = create_op("sell", 10.00, 5000);
+        let (new_state, tax) = state.handle_sell(&sell, &SimConfig::default()).unwrap();
+        assert_eq!(tax, 0.00);
+        assert_eq!(new_state, State::new(5000, 10.00, 1000.00));
+    }
+
+    #[test]
+    fn test_compute_taxes_verbose_reports_loss_applied() {
+        let ops = vec![
+            create_op("buy", 10.00, 10000),
+            create_op("sell", 2.00, 5000),
+            create_op("sell", 20.00, 2000),
+            create_op("sell", 20.00, 2000),
+            create_op("sell", 25.00, 1000),
+        ];
+        let details = compute_taxes_verbose(&ops).unwrap();
+        let loss_applied: Vec<f64> = details.iter().map(|detail| detail.loss_applied).collect();
+        assert_eq!(loss_applied, vec![0.00, 0.00, 20000.00, 20000.00, 0.00]);
+
+        // The loss of the losing sell is fully applied before the final taxed sell.
+        assert_eq!(loss_applied.iter().sum::<f64>(), 40000.00);
+        let taxes: Vec<f64> = details.iter().map(|detail| detail.tax).collect();
+        assert_eq!(taxes, compute_taxes(&ops).unwrap());
+    }
+
+    #[test]
+    fn test_batch_buys_matches_per_op_buys() {
+        let ops: Vec<Op> = (0..100_000)
+            .map(|i| create_op("buy", 10.00 + (i % 100) as f64 / 100.0, i % 50 + 1))
+            .collect();
+
+        let batched = SimConfig {
+            batch_buys: true,
+            ..SimConfig::default()
+        };
+        let (per_op_taxes, per_op) =
+            run_operations(State::default(), &ops, &SimConfig::default()).unwrap();
+        let (batched_taxes, batched) = run_operations(State::default(), &ops, &batched).unwrap();
+        assert_eq!(batched_taxes, per_op_taxes);
+        assert_eq!(batched.total_shares, per_op.total_shares);
 
-    fn create_op(operation: &str, unit_cost: f64, quantity: usize) -> Op {
-        Op {
-            operation: operation.into(),
-            unit_cost,
-            quantity,
-        }
+        // Rounding once per run keeps the batched average within half a cent
+        // of the exact basis, while the per-op average drifts.
+        let exact_average = per_op.weighted_average - audit_basis(&ops).unwrap();
+        assert!((batched.weighted_average - exact_average).abs() <= 0.005);
+        // Over 100k buys, the per-op average drifts by less than ten cents.
+        assert!((batched.weighted_average - per_op.weighted_average).abs() <= 0.10);
     }
 
     #[test]
-    fn test_calculate_weighted_average() {
-        // Test case 1: Simple case
-        assert_eq!(calculate_weighted_avarage(100, 10.0, 50, 20.0), 13.33);
+    fn test_batch_buys_checks_every_buy() {
+        let batched = SimConfig {
+            batch_buys: true,
+            ..SimConfig::default()
+        };
+        let empty_fills = Op {
+            fills: vec![(10.00, 0)],
+            ..create_op("buy", 0.00, 0)
+        };
+        let huge = create_op("buy", f64::MAX, 2);
+        for invalid in [empty_fills, huge] {
+            let ops = vec![
+                create_op("buy", 10.00, 100),
+                invalid,
+                create_op("buy", 10.00, 100),
+            ];
+            let per_op = run_operations(State::default(), &ops, &SimConfig::default());
+            assert!(per_op.is_err());
+            assert_eq!(run_operations(State::default(), &ops, &batched), per_op);
+        }
 
-        // Test case 2: No existing shares
-        assert_eq!(calculate_weighted_avarage(0, 0.0, 100, 15.0), 15.00);
+        // A run of buys of no shares leaves the position unchanged.
+        let ops = vec![
+            create_op("buy", 10.00, 100),
+            create_op("sell", 10.00, 0),
+            create_op("buy", 20.00, 0),
+            create_op("buy", 30.00, 0),
+        ];
+        let (_, state) = run_operations(State::default(), &ops, &batched).unwrap();
+        assert_eq!(state, State::new(100, 10.00, 0.0));
+    }
 
-        // Test case 3: No new shares
-        assert_eq!(calculate_weighted_avarage(100, 10.0, 0, 0.0), 10.00);
+    #[test]
+    fn test_notes_pass_through_to_verbose_output() {
+        let json = r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000, "note": "T-1"}, {"operation":"sell", "unit-cost":20.00, "quantity": 5000}, {"operation":"sell", "unit-cost":5.00, "quantity": 5000, "note": "T-3"}]"#;
+        let ops: Vec<Op> = serde_json::from_str(json).expect("Invalid JSON");
+        let details = compute_taxes_verbose(&ops).unwrap();
 
-        // Test case 4: Large numbers
+        let notes: Vec<Option<&str>> = details
+            .iter()
+            .map(|detail| detail.note.as_deref())
+            .collect();
+        assert_eq!(notes, vec![Some("T-1"), None, Some("T-3")]);
+        assert_eq!(details[1].tax, 10000.00);
         assert_eq!(
-            calculate_weighted_avarage(1_000_000, 50.0, 500_000, 60.0),
-            53.33
+            serde_json::to_string(&details[2]).unwrap(),
+            r#"{"tax":0.0,"loss-applied":0.0,"note":"T-3"}"#
         );
-
-        // Test case 5: Fractional prices
-        assert_eq!(calculate_weighted_avarage(200, 15.75, 100, 16.25), 15.92);
     }
 
     #[test]
-    fn test_case_1() {
+    fn test_min_sell_quantity() {
+        let config = SimConfig {
+            min_sell_quantity: Some(100),
+            ..SimConfig::default()
+        };
+        let ops = vec![create_op("buy", 10.00, 150), create_op("sell", 15.00, 50)];
+        assert_eq!(
+            compute_taxes_with_config(&ops, &config),
+            Err(SimError::BelowMinLot {
+                quantity: 50,
+                minimum: 100,
+            })
+        );
+
+        // Liquidating the whole position is allowed below the minimum.
         let ops = vec![
-            create_op("buy", 10.00, 100),
-            create_op("sell", 15.00, 50),
+            create_op("buy", 10.00, 150),
+            create_op("sell", 15.00, 100),
             create_op("sell", 15.00, 50),
         ];
-        let result = compute_taxes(&ops);
+        let result = compute_taxes_with_config(&ops, &config).unwrap();
         assert_eq!(result, vec![0.00, 0.00, 0.00]);
+        assert_eq!(can_execute_with_config(&ops, &config), Ok(()));
+
+        let ops = vec![create_op("buy", 10.00, 200), create_op("sell", 15.00, 5)];
+        assert_eq!(
+            can_execute_with_config(&ops, &config),
+            Err(SimError::Operation {
+                index: 1,
+                error: Box::new(SimError::BelowMinLot {
+                    quantity: 5,
+                    minimum: 100,
+                }),
+            })
+        );
     }
 
     #[test]
-    fn test_case_2() {
+    fn test_return_of_capital_reduces_basis() {
         let ops = vec![
-            create_op("buy", 10.00, 10000),
-            create_op("sell", 20.00, 5000),
-            create_op("sell", 5.00, 5000),
+            create_op("buy", 10.00, 1000),
+            create_op("return-of-capital", 2.00, 1000),
         ];
-        let result = compute_taxes(&ops);
-        assert_eq!(result, vec![0.00, 10000.00, 0.00]);
+        let (taxes, state) = compute_taxes_with_final_state(&ops).unwrap();
+        assert_eq!(taxes, vec![0.00, 0.00]);
+        assert_eq!(state, State::new(1000, 8.00, 0.0));
+
+        let json = r#"{"operation":"return-of-capital","unit-cost":2.0,"quantity":1000}"#;
+        let op: Op = serde_json::from_str(json).expect("Invalid JSON");
+        assert_eq!(serde_json::to_string(&op).unwrap(), json);
+        assert_eq!(op.operation.name(), "return-of-capital");
     }
 
     #[test]
-    fn test_case_1_and2() {
-        let cases = [
-            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell", "unit-cost":15.00, "quantity": 50},{"operation":"sell", "unit-cost":15.00, "quantity": 50}]"#,
-            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":20.00, "quantity": 5000}, {"operation":"sell", "unit-cost":5.00, "quantity": 5000}]"#,
+    fn test_return_of_capital_beyond_basis_is_taxed() {
+        let ops = vec![
+            create_op("buy", 10.00, 1000),
+            create_op("return-of-capital", 25.00, 1000),
         ];
+        let (taxes, state) = compute_taxes_with_final_state(&ops).unwrap();
+        assert_eq!(taxes, vec![0.00, 3000.00]);
+        assert_eq!(state, State::new(1000, 0.00, 0.0));
+    }
 
-        let results: Vec<Vec<Tax>> = cases
-            .iter()
-            .map(|case| {
-                let ops: Vec<Op> = serde_json::from_str(case).expect("Invalid JSON");
-                compute_taxes(&ops)
+    #[test]
+    fn test_return_of_capital_must_cover_every_share() {
+        let state = State::new(100, 10.00, 0.0);
+        let config = SimConfig::default();
+        assert_eq!(
+            state.handle_roc(&create_op("return-of-capital", 1.00, 1000), &config),
+            Err(SimError::Oversell {
+                requested: 1000,
+                available: 100,
             })
-            .collect();
+        );
+        assert_eq!(
+            state.handle_roc(&create_op("return-of-capital", 1.00, 50), &config),
+            Err(SimError::PartialDistribution {
+                quantity: 50,
+                held: 100,
+            })
+        );
 
+        let ops = vec![
+            create_op("buy", 10.00, 100),
+            create_op("return-of-capital", 1.00, 50),
+        ];
         assert_eq!(
-            results,
-            vec![vec![0.00, 0.00, 0.00], vec![0.00, 10000.00, 0.00]]
+            can_execute(&ops),
+            Err(SimError::Operation {
+                index: 1,
+                error: Box::new(SimError::PartialDistribution {
+                    quantity: 50,
+                    held: 100,
+                }),
+            })
         );
+        assert!(compute_taxes(&ops).is_err());
     }
 
     #[test]
-    fn test_case_3() {
+    fn test_return_of_capital_without_position() {
+        let roc = create_op("return-of-capital", 2.00, 1000);
+        let error = SimError::NoPosition { quantity: 1000 };
+        assert_eq!(
+            compute_taxes(std::slice::from_ref(&roc)),
+            Err(error.clone())
+        );
+
+        let short = State::new(-1000, 10.00, 0.0);
+        assert_eq!(
+            short.handle_roc(&roc, &SimConfig::default()),
+            Err(error.clone())
+        );
+
         let ops = vec![
+            create_op("buy", 10.00, 1000),
+            create_op("sell", 10.00, 1000),
+            roc,
+        ];
+        assert!(compute_taxes(&ops).is_err());
+        assert_eq!(
+            can_execute(&ops),
+            Err(SimError::Operation {
+                index: 2,
+                error: Box::new(error),
+            })
+        );
+    }
+
+    #[test]
+    fn test_save_and_load_state_resumes_processing() {
+        let operations = vec![
             create_op("buy", 10.00, 10000),
-            create_op("sell", 5.00, 5000),
-            create_op("sell", 20.00, 3000),
+            create_op("sell", 2.00, 5000),
+            create_op("sell", 20.00, 2000),
+            create_op("buy", 20.00, 1000),
+            create_op("sell", 25.00, 3000),
         ];
-        let result = compute_taxes(&ops);
-        assert_eq!(result, vec![0.00, 0.00, 1000.00]);
+        let (first_day, second_day) = operations.split_at(2);
+
+        let (state, mut taxes) = State::default().apply_batch(first_day).unwrap();
+        let saved = save_state(&state);
+        assert_eq!(
+            saved,
+            r#"{"total-shares":5000,"weighted-average":10.0,"accumulated-loss":40000.0}"#
+        );
+
+        let restored = load_state(&saved).unwrap();
+        assert_eq!(restored, state);
+        let (final_state, resumed_taxes) = restored.apply_batch(second_day).unwrap();
+        taxes.extend(resumed_taxes);
+
+        let (uninterrupted_taxes, uninterrupted_state) =
+            compute_taxes_with_final_state(&operations).unwrap();
+        assert_eq!(taxes, uninterrupted_taxes);
+        assert_eq!(final_state, uninterrupted_state);
+
+        assert!(load_state(r#"{"total-shares":5000}"#).is_err());
     }
 
     #[test]
-    fn test_case_4() {
+    fn test_class_thresholds() {
+        let config = SimConfig {
+            class_thresholds: HashMap::from([
+                ("stock".to_string(), 20000.00),
+                ("etf".to_string(), 0.00),
+            ]),
+            ..SimConfig::default()
+        };
+        let sell_of_class = |class: &str| {
+            let mut sell = create_op("sell", 15.00, 1000);
+            sell.asset_class = Some(class.to_string());
+            vec![create_op("buy", 10.00, 1000), sell]
+        };
+
+        let stock = compute_taxes_with_config(&sell_of_class("stock"), &config).unwrap();
+        assert_eq!(stock, vec![0.00, 0.00]);
+        let etf = compute_taxes_with_config(&sell_of_class("etf"), &config).unwrap();
+        assert_eq!(etf, vec![0.00, 1000.00]);
+        // Classes without a threshold of their own use the global one.
+        let bond = compute_taxes_with_config(&sell_of_class("bond"), &config).unwrap();
+        assert_eq!(bond, vec![0.00, 0.00]);
+    }
+
+    #[test]
+    fn test_tax_approx_eq() {
+        let tax = Tax::new(100.00);
+        assert!(tax.approx_eq(&Tax::new(100.001), 0.01));
+        assert!(Tax::new(100.001).approx_eq(&tax, 0.01));
+        assert!(!tax.approx_eq(&Tax::new(100.1), 0.01));
+        assert!(!Tax::new(100.1).approx_eq(&tax, 0.01));
+    }
+
+    #[test]
+    fn test_handle_operation_with_config() {
+        let config = SimConfig {
+            tax_rate: 0.15,
+            ..SimConfig::default()
+        };
+        let state = State::new(10000, 10.00, 0.0);
+        let sell = create_op("sell", 20.00, 5000);
+
+        let (new_state, tax) = handle_operation_with_config(state.clone(), &sell, &config).unwrap();
+        assert_eq!(tax, 7500.00);
+        assert_eq!(new_state, State::new(5000, 10.00, 0.0));
+        assert_eq!(
+            handle_operation(state.clone(), &sell).unwrap(),
+            handle_operation_with_config(state, &sell, &SimConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_total_value_overflow() {
+        // usize::MAX shares at 1e200 is about 1.8e219, still within range.
+        let large = create_op("buy", 1e200, usize::MAX);
+        assert_eq!(large.total_value_checked(), Some(large.total_value()));
+
+        let overflowing = create_op("buy", 1e300, usize::MAX);
+        assert_eq!(overflowing.total_value_checked(), None);
+        let error = compute_taxes(&[overflowing]).unwrap_err();
+        assert_eq!(
+            error,
+            SimError::Overflow {
+                unit_cost: 1e300,
+                quantity: usize::MAX,
+            }
+        );
+        assert_eq!(error.kind(), "overflow");
+    }
+
+    #[test]
+    fn test_threshold_exemption_disabled() {
         let ops = vec![
-            create_op("buy", 10.00, 10000),
-            create_op("buy", 25.00, 5000),
-            create_op("sell", 15.00, 10000),
+            create_op("buy", 10.00, 100),
+            create_op("sell", 15.00, 50),
+            create_op("sell", 15.00, 50),
         ];
-        let result = compute_taxes(&ops);
-        assert_eq!(result, vec![0.00, 0.00, 0.00]);
+        let config = SimConfig {
+            apply_threshold_exemption: false,
+            ..SimConfig::default()
+        };
+        assert_taxes_approx(&compute_taxes(&ops).unwrap(), &[0.00, 0.00, 0.00]);
+        assert_taxes_approx(
+            &compute_taxes_with_config(&ops, &config).unwrap(),
+            &[0.00, 50.00, 50.00],
+        );
+
+        let state = State::new(100, 10.00, 0.0);
+        assert_eq!(state.break_even_price(&config), 10.00);
     }
 
     #[test]
-    fn test_case_5() {
+    fn test_max_tax_free_quantity() {
+        let config = SimConfig::default();
+        let state = State::new(10000, 10.00, 0.0);
+        assert_eq!(state.max_tax_free_quantity(30.00, None, &config), 666);
+        let sell = create_op("sell", 30.00, 666);
+        assert_eq!(state.handle_sell(&sell, &config).unwrap().1, 0.00);
+        let sell = create_op("sell", 30.00, 667);
+        assert!(state.handle_sell(&sell, &config).unwrap().1.tax > 0.0);
+
+        // At or below the weighted average there is no profit to tax.
+        assert_eq!(state.max_tax_free_quantity(10.00, None, &config), 10000);
+        assert_eq!(state.max_tax_free_quantity(5.00, None, &config), 10000);
+        // The quantity is capped at the shares held.
+        assert_eq!(
+            State::new(100, 10.00, 0.0).max_tax_free_quantity(30.00, None, &config),
+            100
+        );
+        assert_eq!(
+            State::default().max_tax_free_quantity(30.00, None, &config),
+            0
+        );
+    }
+
+    #[test]
+    fn test_max_tax_free_quantity_uses_class_threshold() {
+        let config = SimConfig {
+            class_thresholds: HashMap::from([("crypto".to_string(), 35000.00)]),
+            ..SimConfig::default()
+        };
+        let state = State::new(10000, 10.00, 0.0);
+        let quantity = state.max_tax_free_quantity(30.00, Some("crypto"), &config);
+        assert_eq!(quantity, 1166);
+
+        let sell = |quantity| Op {
+            asset_class: Some("crypto".to_string()),
+            ..create_op("sell", 30.00, quantity)
+        };
+        assert_eq!(state.handle_sell(&sell(1166), &config).unwrap().1, 0.00);
+        assert!(state.handle_sell(&sell(1167), &config).unwrap().1.tax > 0.0);
+
+        // Other classes fall back to the global threshold.
+        assert_eq!(
+            state.max_tax_free_quantity(30.00, Some("stock"), &config),
+            666
+        );
+    }
+
+    #[test]
+    fn test_sell_without_position() {
+        let ops = vec![create_op("sell", 20.00, 100), create_op("buy", 10.00, 100)];
+        let error = compute_taxes(&ops).unwrap_err();
+        assert_eq!(error, SimError::NoPosition { quantity: 100 });
+        assert_eq!(error.kind(), "no_position");
+        assert_eq!(
+            can_execute(&ops),
+            Err(SimError::Operation {
+                index: 0,
+                error: Box::new(SimError::NoPosition { quantity: 100 }),
+            })
+        );
+
+        // Selling out a position and then selling again is also rejected.
         let ops = vec![
-            create_op("buy", 10.00, 10000),
-            create_op("buy", 25.00, 5000),
-            create_op("sell", 15.00, 10000),
-            create_op("sell", 25.00, 5000),
+            create_op("buy", 10.00, 100),
+            create_op("sell", 15.00, 100),
+            create_op("sell", 15.00, 10),
         ];
-        let result = compute_taxes(&ops);
-        assert_eq!(result, vec![0.00, 0.00, 0.00, 10000.00]);
+        assert_eq!(
+            compute_taxes(&ops),
+            Err(SimError::NoPosition { quantity: 10 })
+        );
     }
 
     #[test]
-    fn test_case_6() {
+    fn test_diff_taxes() {
         let ops = vec![
             create_op("buy", 10.00, 10000),
-            create_op("sell", 2.00, 5000),
-            create_op("sell", 20.00, 2000),
-            create_op("sell", 20.00, 2000),
+            create_op("sell", 20.00, 5000),
+            create_op("sell", 5.00, 1000),
             create_op("sell", 25.00, 1000),
         ];
-        let result = compute_taxes(&ops);
-        assert_eq!(result, vec![0.00, 0.00, 0.00, 0.00, 3000.00]);
+        let config = SimConfig {
+            tax_rate: 0.15,
+            ..SimConfig::default()
+        };
+        let standard = compute_taxes(&ops).unwrap();
+        let reduced = compute_taxes_with_config(&ops, &config).unwrap();
+
+        assert_eq!(
+            diff_taxes(&standard, &reduced, 0.01),
+            vec![
+                (1, Some(10000.00), Some(7500.00)),
+                (3, Some(2000.00), Some(1500.00)),
+            ]
+        );
+        assert!(diff_taxes(&standard, &standard, 0.01).is_empty());
+
+        // Operations only one series has are reported too.
+        assert_eq!(
+            diff_taxes(&standard[..3], &standard, 0.01),
+            vec![(3, None, Some(2000.00))]
+        );
+        assert_eq!(
+            diff_taxes(&standard, &standard[..2], 0.01),
+            vec![(2, Some(0.00), None), (3, Some(2000.00), None)]
+        );
     }
 
     #[test]
-    fn test_case_7() {
+    fn test_engine_cancel() {
+        let mut engine = TaxEngine::new();
+        engine
+            .push(&create_op_with_note("buy", 10.00, 10000, "t1"))
+            .unwrap();
+        let before_sell = engine.state().clone();
+        assert_eq!(
+            engine
+                .push(&create_op_with_note("sell", 20.00, 5000, "t2"))
+                .unwrap(),
+            &Tax::new(10000.00)
+        );
+
+        assert_eq!(
+            engine
+                .push(&create_op_with_note("cancel", 0.00, 0, "t2"))
+                .unwrap(),
+            &Tax::new(-10000.00)
+        );
+        assert_eq!(engine.state(), &before_sell);
+
+        // A cancelled operation cannot be cancelled again.
+        let error = engine
+            .push(&create_op_with_note("cancel", 0.00, 0, "t2"))
+            .unwrap_err();
+        assert_eq!(
+            error,
+            SimError::CancelNotFound {
+                id: "t2".to_string()
+            }
+        );
+        assert_eq!(engine.state(), &before_sell);
+
+        // Undoing the cancel puts the sell back in effect.
+        assert_eq!(engine.undo(), Some(Tax::new(-10000.00)));
+        assert_eq!(engine.state(), &State::new(5000, 10.00, 0.0));
+
+        assert!(compute_taxes(&[create_op_with_note("cancel", 0.00, 0, "t1")]).is_err());
+        assert_eq!(
+            can_execute(&[create_op_with_note("cancel", 0.00, 0, "t1")]),
+            Err(SimError::Operation {
+                index: 0,
+                error: Box::new(SimError::CancelNotFound {
+                    id: "t1".to_string()
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn test_compute_detailed() {
         let ops = vec![
             create_op("buy", 10.00, 10000),
             create_op("sell", 2.00, 5000),
@@ -374,22 +3292,315 @@ mod tests {
             create_op("sell", 30.00, 4350),
             create_op("sell", 30.00, 650),
         ];
-        let result = compute_taxes(&ops);
+        let results = compute_detailed(&ops).unwrap();
+        assert_eq!(results.len(), ops.len());
+        assert_eq!(
+            results[4],
+            OpResult {
+                tax: Tax::new(3000.00),
+                realized_gain: 15000.00,
+                loss_applied: 0.00,
+                proceeds: 22000.00,
+                state: State::new(0, 10.00, 0.00),
+            }
+        );
+        assert_eq!(
+            results[7],
+            OpResult {
+                tax: Tax::new(3700.00),
+                realized_gain: 43500.00,
+                loss_applied: 25000.00,
+                proceeds: 126800.00,
+                state: State::new(650, 20.00, 0.00),
+            }
+        );
+
+        let taxes: Vec<Tax> = results.into_iter().map(|result| result.tax).collect();
+        assert_eq!(taxes, compute_taxes(&ops).unwrap());
+        assert_eq!(
+            serde_json::to_value(&compute_detailed(&ops[..1]).unwrap()[0]).unwrap(),
+            serde_json::json!({
+                "tax": { "tax": 0.0 },
+                "realized-gain": 0.0,
+                "loss-applied": 0.0,
+                "proceeds": -100000.0,
+                "state": {
+                    "total-shares": 10000,
+                    "weighted-average": 10.0,
+                    "accumulated-loss": 0.0,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_share_count_overflow() {
+        // A zero-cost buy has a total value of zero, whatever its quantity.
+        let huge = vec![create_op("buy", 0.00, usize::MAX)];
+        let overflow = SimError::Overflow {
+            unit_cost: 0.00,
+            quantity: usize::MAX,
+        };
+        assert_eq!(compute_taxes(&huge), Err(overflow.clone()));
+        assert_eq!(
+            can_execute(&huge),
+            Err(SimError::Operation {
+                index: 0,
+                error: Box::new(overflow),
+            })
+        );
+
+        let max = i64::MAX as usize;
+        let ops = vec![create_op("buy", 0.00, max), create_op("buy", 0.00, 1)];
+        let overflow = SimError::Overflow {
+            unit_cost: 0.00,
+            quantity: 1,
+        };
+        assert_eq!(compute_taxes(&ops), Err(overflow.clone()));
+        assert_eq!(
+            can_execute(&ops),
+            Err(SimError::Operation {
+                index: 1,
+                error: Box::new(overflow),
+            })
+        );
+
+        let config = SimConfig {
+            allow_short: true,
+            ..SimConfig::default()
+        };
+        let ops = vec![create_op("sell", 0.00, max), create_op("sell", 0.00, 2)];
         assert_eq!(
-            result,
-            vec![0.00, 0.00, 0.00, 0.00, 3000.00, 0.00, 0.00, 3700.00, 0.00]
+            compute_taxes_with_config(&ops, &config),
+            Err(SimError::Overflow {
+                unit_cost: 0.00,
+                quantity: 2,
+            })
         );
     }
 
     #[test]
-    fn test_case_8() {
+    fn test_exempt_sell_reduces_shares() {
         let ops = vec![
-            create_op("buy", 10.00, 10000),
-            create_op("sell", 50.00, 10000),
-            create_op("buy", 20.00, 10000),
-            create_op("sell", 50.00, 10000),
+            create_op("buy", 10.00, 100),
+            create_op("sell", 15.00, 50),
+            create_op("buy", 20.00, 50),
+        ];
+        let (taxes, state) = compute_taxes_with_final_state(&ops).unwrap();
+        assert_taxes_approx(&taxes, &[0.00, 0.00, 0.00]);
+        // The exempt sell leaves 50 shares at 10.00 to average with the new buy.
+        assert_eq!(state, State::new(100, 15.00, 0.0));
+
+        let ops = vec![
+            create_op("buy", 10.00, 100),
+            create_op("sell", 15.00, 50),
+            create_op("sell", 15.00, 60),
+        ];
+        assert_eq!(
+            compute_taxes(&ops),
+            Err(SimError::Oversell {
+                requested: 60,
+                available: 50,
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_fills_are_rejected() {
+        let buy = |fills: Vec<(f64, usize)>| Op {
+            fills,
+            ..create_op("buy", 0.00, 0)
+        };
+        let zero = vec![buy(vec![(10.00, 0), (11.00, 0)])];
+        assert_eq!(compute_taxes(&zero), Err(SimError::EmptyFills));
+        assert_eq!(
+            can_execute(&zero),
+            Err(SimError::Operation {
+                index: 0,
+                error: Box::new(SimError::EmptyFills),
+            })
+        );
+
+        let overflowing = vec![buy(vec![(1e300, usize::MAX)])];
+        assert_eq!(compute_taxes(&overflowing).unwrap_err().kind(), "overflow");
+
+        let sell = Op {
+            fills: vec![(15.00, 50)],
+            ..create_op("sell", 15.00, 50)
+        };
+        let ops = vec![create_op("buy", 10.00, 100), sell.clone()];
+        let error = compute_taxes(&ops).unwrap_err();
+        assert_eq!(error, SimError::UnexpectedFills);
+        assert_eq!(error.kind(), "unexpected_fills");
+        assert_eq!(
+            State::new(100, 10.00, 0.0).handle_sell(&sell, &SimConfig::default()),
+            Err(SimError::UnexpectedFills)
+        );
+    }
+
+    #[test]
+    fn test_engine_rejects_duplicate_notes() {
+        let mut engine = TaxEngine::new();
+        engine
+            .push(&create_op_with_note("buy", 10.00, 100, "t1"))
+            .unwrap();
+        let error = engine
+            .push(&create_op_with_note("buy", 10.00, 50, "t1"))
+            .unwrap_err();
+        assert_eq!(
+            error,
+            SimError::DuplicateNote {
+                id: "t1".to_string()
+            }
+        );
+        assert_eq!(error.kind(), "duplicate_note");
+        assert_eq!(engine.state(), &State::new(100, 10.00, 0.0));
+
+        // Once cancelled, the note can be used again.
+        engine
+            .push(&create_op_with_note("cancel", 0.00, 0, "t1"))
+            .unwrap();
+        engine
+            .push(&create_op_with_note("buy", 10.00, 50, "t1"))
+            .unwrap();
+        assert_eq!(engine.state(), &State::new(50, 10.00, 0.0));
+
+        // Operations without a note can never be cancelled.
+        engine.push(&create_op("buy", 10.00, 10)).unwrap();
+        assert_eq!(
+            engine.push(&create_op("cancel", 0.00, 0)).unwrap_err(),
+            SimError::CancelNotFound { id: String::new() }
+        );
+    }
+
+    #[test]
+    fn test_engine_history_limit() {
+        let mut engine = TaxEngine::new().with_history_limit(2);
+        engine.push(&create_op("buy", 10.00, 10000)).unwrap();
+        engine.push(&create_op("sell", 20.00, 5000)).unwrap();
+        engine.push(&create_op("sell", 5.00, 1000)).unwrap();
+        assert_eq!(engine.taxes(), &[Tax::new(10000.00), Tax::new(0.00)]);
+
+        assert_eq!(engine.undo(), Some(Tax::new(0.00)));
+        assert_eq!(engine.undo(), Some(Tax::new(10000.00)));
+        // The buy was dropped from the history, so it cannot be undone.
+        assert_eq!(engine.undo(), None);
+        assert_eq!(engine.state(), &State::new(10000, 10.00, 0.0));
+
+        engine
+            .push(&create_op_with_note("buy", 10.00, 100, "t1"))
+            .unwrap();
+        engine.push(&create_op("buy", 10.00, 100)).unwrap();
+        engine.push(&create_op("buy", 10.00, 100)).unwrap();
+        assert_eq!(
+            engine
+                .push(&create_op_with_note("cancel", 0.00, 0, "t1"))
+                .unwrap_err(),
+            SimError::CancelNotFound {
+                id: "t1".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_engine_cancel_replays_later_operations() {
+        let mut engine = TaxEngine::new();
+        engine
+            .push(&create_op_with_note("buy", 10.00, 10000, "t1"))
+            .unwrap();
+        engine
+            .push(&create_op_with_note("buy", 20.00, 10000, "t2"))
+            .unwrap();
+        assert_eq!(
+            engine
+                .push(&create_op_with_note("sell", 30.00, 10000, "t3"))
+                .unwrap(),
+            &Tax::new(30000.00)
+        );
+
+        // Without the second buy, the sell realizes 200000 instead of 150000.
+        assert_eq!(
+            engine
+                .push(&create_op_with_note("cancel", 0.00, 0, "t2"))
+                .unwrap(),
+            &Tax::new(10000.00)
+        );
+        assert_eq!(engine.state(), &State::new(0, 10.00, 0.0));
+
+        // Cancelling the sell as well reverses all the tax paid on it.
+        assert_eq!(
+            engine
+                .push(&create_op_with_note("cancel", 0.00, 0, "t3"))
+                .unwrap(),
+            &Tax::new(-40000.00)
+        );
+        assert_eq!(engine.state(), &State::new(10000, 10.00, 0.0));
+
+        // Undoing both cancels brings back the original portfolio.
+        engine.undo();
+        assert_eq!(engine.state(), &State::new(0, 10.00, 0.0));
+        engine.undo();
+        assert_eq!(engine.state(), &State::new(10000, 15.00, 0.0));
+        assert_eq!(
+            engine
+                .push(&create_op_with_note("cancel", 0.00, 0, "t3"))
+                .unwrap(),
+            &Tax::new(-30000.00)
+        );
+        assert_eq!(engine.state(), &State::new(20000, 15.00, 0.0));
+    }
+
+    #[test]
+    fn test_loss_applied_only_counts_loss_offsetting_taxed_gains() {
+        let config = SimConfig {
+            exempt_gains_consume_loss: true,
+            ..SimConfig::default()
+        };
+        let state = State::new(10000, 10.00, 5000.00);
+
+        // An exempt gain consumes the loss without any of it reducing a tax.
+        let (new_state, tax, outcome) = state
+            .sell(&create_op("sell", 20.00, 100), &config, &linear_tax)
+            .unwrap();
+        assert_eq!(new_state.accumulated_loss, 4000.00);
+        assert_eq!(tax, 0.00);
+        assert_eq!(outcome.loss_applied, 0.00);
+
+        // A taxed gain is reduced by as much of the loss as it can absorb.
+        let (new_state, tax, outcome) = state
+            .sell(&create_op("sell", 20.00, 5000), &config, &linear_tax)
+            .unwrap();
+        assert_eq!(new_state.accumulated_loss, 0.00);
+        assert_eq!(tax, 9000.00);
+        assert_eq!(outcome.loss_applied, 5000.00);
+    }
+
+    #[test]
+    fn test_compute_detailed_with_config() {
+        let config = SimConfig {
+            convert_to_base: true,
+            exempt_gains_consume_loss: true,
+            ..SimConfig::default()
+        };
+        let with_fx = |operation: &str, unit_cost: f64, quantity: usize| Op {
+            fx_rate: Some(2.0),
+            ..create_op(operation, unit_cost, quantity)
+        };
+        let ops = vec![
+            with_fx("buy", 10.00, 1000),
+            with_fx("sell", 5.00, 500),
+            with_fx("sell", 15.00, 100),
+            with_fx("sell", 30.00, 400),
         ];
-        let result = compute_taxes(&ops);
-        assert_eq!(result, vec![0.00, 80000.00, 0.00, 60000.00]);
+        let results = compute_detailed_with_config(&ops, &config).unwrap();
+
+        let gains: Vec<f64> = results.iter().map(|result| result.realized_gain).collect();
+        assert_eq!(gains, vec![0.00, -5000.00, 1000.00, 16000.00]);
+        // The exempt gain consumes loss that no tax is reduced by.
+        let loss_applied: Vec<f64> = results.iter().map(|result| result.loss_applied).collect();
+        assert_eq!(loss_applied, vec![0.00, 0.00, 0.00, 4000.00]);
+        let proceeds: Vec<f64> = results.iter().map(|result| result.proceeds).collect();
+        assert_eq!(proceeds, vec![-20000.00, 5000.00, 3000.00, 21600.00]);
+        assert_eq!(results[3].tax, 2400.00);
     }
 }
@@ -0,0 +1,250 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(input: &str) -> Output {
+    run_with_args(&[], input)
+}
+
+fn run_with_args(args: &[&str], input: &str) -> Output {
+    run_bytes(args, input.as_bytes())
+}
+
+fn run_bytes(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_capital_gains"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn binary");
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_single_array_per_line() {
+    let output = run(concat!(
+        r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell", "unit-cost":15.00, "quantity": 50}]"#,
+        "\n",
+    ));
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[{\"tax\":0.0},{\"tax\":0.0}]\n"
+    );
+}
+
+#[test]
+fn test_concatenated_arrays_on_one_line() {
+    let output = run(concat!(
+        r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell", "unit-cost":15.00, "quantity": 50}]"#,
+        r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":20.00, "quantity": 5000}]"#,
+        "\n",
+    ));
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[{\"tax\":0.0},{\"tax\":0.0}]\n[{\"tax\":0.0},{\"tax\":10000.0}]\n"
+    );
+}
+
+#[test]
+fn test_bad_lines_are_reported_and_skipped() {
+    let output = run(concat!(
+        r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell", "unit-cost":15.00, "quantity": 50}]"#,
+        "\n",
+        r#"[{"operation":"buy", "unit-cost":10.00"#,
+        "\n",
+        r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell", "unit-cost":15.00, "quantity": 150}]"#,
+        "\n",
+    ));
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[{\"tax\":0.0},{\"tax\":0.0}]\n"
+    );
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let errors: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("stderr is not JSON"))
+        .collect();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0]["line"], 2);
+    assert_eq!(errors[0]["column"], 38);
+    assert_eq!(errors[0]["kind"], "parse");
+    assert_eq!(errors[1]["line"], 3);
+    assert_eq!(errors[1]["kind"], "oversell");
+    assert_eq!(
+        errors[1]["error"],
+        "cannot sell 150 shares, only 100 available"
+    );
+}
+
+#[test]
+fn test_lines_that_are_not_utf8_are_reported_and_skipped() {
+    let mut input = b"\xff\n".to_vec();
+    input.extend_from_slice(br#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}]"#);
+    input.push(b'\n');
+    let output = run_bytes(&[], &input);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[{\"tax\":0.0}]\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error: serde_json::Value = serde_json::from_str(stderr.trim()).unwrap();
+    assert_eq!(error["line"], 1);
+    assert_eq!(error["kind"], "io");
+}
+
+#[test]
+fn test_object_lines_are_applied_incrementally() {
+    let output = run(concat!(
+        r#"{"operation":"buy", "unit-cost":10.00, "quantity": 10000}"#,
+        "\n",
+        r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 100}, {"operation":"sell", "unit-cost":15.00, "quantity": 50}]"#,
+        "\n",
+        r#"{"operation":"sell", "unit-cost":20.00, "quantity": 5000}"#,
+        "\n",
+        r#"{"operation":"sell", "unit-cost":5.00, "quantity": 5000}"#,
+        "\n",
+    ));
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "{\"tax\":0.0}\n[{\"tax\":0.0},{\"tax\":0.0}]\n{\"tax\":10000.0}\n{\"tax\":0.0}\n"
+    );
+}
+
+#[test]
+fn test_ndjson_flattens_batches() {
+    let input = include_str!("../fixtures/fixtures.json");
+    let operations: usize = input
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<Vec<serde_json::Value>>(line)
+                .unwrap()
+                .len()
+        })
+        .sum();
+
+    let output = run_with_args(&["--ndjson"], input);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), operations);
+    for line in stdout.lines() {
+        let tax: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert!(tax["tax"].is_number());
+    }
+}
+
+#[test]
+fn test_cents_output() {
+    let output = run_with_args(
+        &["--cents"],
+        concat!(
+            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":20.00, "quantity": 5000}]"#,
+            "\n",
+        ),
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[{\"tax\":0},{\"tax\":1000000}]\n"
+    );
+}
+
+#[test]
+fn test_verbose_output() {
+    let output = run_with_args(
+        &["--verbose"],
+        concat!(
+            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":5.00, "quantity": 5000}, {"operation":"sell", "unit-cost":20.00, "quantity": 3000}]"#,
+            "\n",
+        ),
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        concat!(
+            r#"[{"loss-applied":0.0,"tax":0.0},{"loss-applied":0.0,"tax":0.0},{"loss-applied":25000.0,"tax":1000.0}]"#,
+            "\n",
+        )
+    );
+}
+
+#[test]
+fn test_verbose_cents_output_on_arrays_and_objects() {
+    let output = run_with_args(
+        &["--verbose", "--cents"],
+        concat!(
+            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":5.00, "quantity": 5000}, {"operation":"sell", "unit-cost":20.00, "quantity": 3000}]"#,
+            "\n",
+            r#"{"operation":"buy", "unit-cost":10.00, "quantity": 10000}"#,
+            "\n",
+            r#"{"operation":"sell", "unit-cost":5.00, "quantity": 5000}"#,
+            "\n",
+            r#"{"operation":"sell", "unit-cost":20.00, "quantity": 3000, "note": "T-3"}"#,
+            "\n",
+        ),
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        concat!(
+            r#"[{"loss-applied":0,"tax":0},{"loss-applied":0,"tax":0},{"loss-applied":2500000,"tax":100000}]"#,
+            "\n",
+            r#"{"loss-applied":0,"tax":0}"#,
+            "\n",
+            r#"{"loss-applied":0,"tax":0}"#,
+            "\n",
+            r#"{"loss-applied":2500000,"note":"T-3","tax":100000}"#,
+            "\n",
+        )
+    );
+}
+
+#[test]
+fn test_unknown_arguments_are_rejected() {
+    let output = run_with_args(&["--cent"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("unknown argument: --cent\nusage: "));
+}
+
+#[test]
+fn test_trace_table() {
+    let output = run_with_args(
+        &["--trace"],
+        concat!(
+            r#"[{"operation":"buy", "unit-cost":10.00, "quantity": 10000}, {"operation":"sell", "unit-cost":5.00, "quantity": 5000}, {"operation":"sell", "unit-cost":20.00, "quantity": 3000}]"#,
+            "\n",
+        ),
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let rows: Vec<Vec<&str>> = stdout
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(
+        rows,
+        vec![
+            vec![
+                "operation",
+                "unit-cost",
+                "quantity",
+                "shares",
+                "average",
+                "loss",
+                "tax"
+            ],
+            vec!["buy", "10.00", "10000", "10000", "10.00", "0.00", "0.00"],
+            vec!["sell", "5.00", "5000", "5000", "10.00", "25000.00", "0.00"],
+            vec!["sell", "20.00", "3000", "2000", "10.00", "0.00", "1000.00"],
+        ]
+    );
+    assert!(!stdout.contains('{'));
+}